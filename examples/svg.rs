@@ -5,18 +5,18 @@ use pathfinder_geometry::transform2d::Transform2F;
 use pathfinder_geometry::vector::{Vector2F, Vector2I};
 use pathfinder_gl::{GLDevice, GLVersion};
 use pathfinder_gpu::resources::FilesystemResourceLoader;
-use pathfinder_gpu::{Device};
+use pathfinder_gpu::{Device, TextureFormat};
 use raqote::{DrawTarget, SolidSource, Transform};
 use resvg::{Options as ResvgOptions, ScreenSize};
 use resvg::backend_raqote;
 use resvg::usvg::{Options as UsvgOptions, Tree};
 use std::env;
-use std::slice;
 use surfman::{Connection, ContextAttributeFlags, ContextAttributes, GLVersion as SurfmanGLVersion};
 use surfman::{SurfaceAccess, SurfaceType};
 use virtex::manager2d::VirtualTextureManager2D;
+use virtex::rasterizer::{ChannelOrder, RasterizeError, TileRasterizer, swizzle_pixels};
 use virtex::renderer_simple::SimpleRenderer;
-use virtex::{TileCacheEntry, VirtualTexture};
+use virtex::{TileCacheEntry, TileDescriptor, VirtualTextureBuilder};
 use winit::dpi::LogicalSize;
 use winit::{DeviceEvent, Event, EventsLoop, KeyboardInput, ModifiersState, MouseScrollDelta};
 use winit::{VirtualKeyCode, WindowBuilder, WindowEvent};
@@ -32,24 +32,39 @@ const TILE_CACHE_WIDTH: u32 = CACHE_TILES_ACROSS * TILE_BACKING_SIZE;
 const TILE_CACHE_HEIGHT: u32 = CACHE_TILES_DOWN * TILE_BACKING_SIZE;
 const DEFAULT_GLOBAL_SCALE_FACTOR: f32 = 5.0;
 
-static BACKGROUND_COLOR: SolidSource = SolidSource { r: 255, g: 255, b: 255, a: 255 };
+static DEFAULT_BACKGROUND_COLOR: SolidSource = SolidSource { r: 255, g: 255, b: 255, a: 255 };
 
 static DEFAULT_SVG_PATH: &'static str = "resources/svg/Ghostscript_Tiger.svg";
 
+// Usage: svg [--transparent] [--supersample=N] [SVG-PATH [SCALE-FACTOR [OVERLAY-PATH...]]]
 fn main() {
-    let svg_path = match env::args().nth(1) {
-        Some(path) => path,
+    // Flags may appear anywhere among the positional arguments.
+    let (flags, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+
+    let svg_path = match args.get(0) {
+        Some(path) => path.clone(),
         None => DEFAULT_SVG_PATH.to_owned(),
     };
 
-    let global_scale_factor: f32 = match env::args().nth(2) {
+    let global_scale_factor: f32 = match args.get(1) {
         None => DEFAULT_GLOBAL_SCALE_FACTOR,
         Some(factor) => factor.parse().unwrap(),
     };
 
-    let svg_tree = Tree::from_file(&svg_path, &UsvgOptions::default()).unwrap();
-    let svg_size = svg_tree.svg_node().size;
-    let svg_size = Vector2I::new(svg_size.width().ceil() as i32, svg_size.height().ceil() as i32);
+    let mut rasterizer = SvgTileRasterizer::from_file(&svg_path, global_scale_factor).unwrap();
+    for overlay_path in args.iter().skip(2) {
+        rasterizer.add_layer(overlay_path, Transform2F::default()).unwrap();
+    }
+    for flag in &flags {
+        let mut flag_parts = flag.splitn(2, '=');
+        match (flag_parts.next().unwrap(), flag_parts.next()) {
+            ("--transparent", None) => rasterizer.set_background_color(|_| None),
+            ("--supersample", Some(factor)) => rasterizer.set_supersample(factor.parse().unwrap()),
+            _ => eprintln!("warning: ignoring unknown flag {}", flag),
+        }
+    }
+    let svg_size = rasterizer.content_size();
 
     let mut event_loop = EventsLoop::new();
     let dpi = event_loop.get_primary_monitor().get_hidpi_factor() as f32;
@@ -92,15 +107,31 @@ fn main() {
 
     // Initialize the cache.
     let cache_texture_size = Vector2I::new(TILE_CACHE_WIDTH as i32, TILE_CACHE_HEIGHT as i32);
-    let mut cache_pixels =
-        vec![0; cache_texture_size.x() as usize * cache_texture_size.y() as usize];
-    let mut cache_draw_target = DrawTarget::new(TILE_BACKING_SIZE as i32,
-                                                TILE_BACKING_SIZE as i32);
 
-    // Initialize the virtual texture.
-    let virtual_texture = VirtualTexture::new(svg_size, cache_texture_size, TILE_SIZE);
+    // Initialize the virtual texture. At LOD 0, the rasterizer scales the SVG by the global scale
+    // factor.
+    let content_size = svg_size.to_f32().scale(global_scale_factor).ceil().to_i32();
+    let virtual_texture = VirtualTextureBuilder::new().virtual_texture_size(content_size)
+                                                      .cache_texture_size(cache_texture_size)
+                                                      .tile_size(TILE_SIZE)
+                                                      .build()
+                                                      .unwrap();
     let manager = VirtualTextureManager2D::new(virtual_texture, physical_window_size);
-    let mut renderer = SimpleRenderer::new(&device, manager, &resources);
+    let mut renderer = SimpleRenderer::new(&device, manager, &resources, TextureFormat::RGBA8);
+
+    // Rasterizers produce 8-bit four-channel pixels, which are copied into the cache unconverted,
+    // so reject any other cache format up front.
+    let cache_channel_order = match ChannelOrder::for_texture_format(renderer.cache_format()) {
+        Some(cache_channel_order) => cache_channel_order,
+        None => {
+            panic!("Cache format {:?} can't hold rasterized tiles; use RGBA8 instead",
+                   renderer.cache_format())
+        }
+    };
+
+    let mut cache_pixels = vec![0; cache_texture_size.x() as usize *
+                                   cache_texture_size.y() as usize *
+                                   renderer.cache_bytes_per_pixel() as usize];
 
     let mut exit = false;
     let mut needed_tiles = vec![];
@@ -108,13 +139,15 @@ fn main() {
     while !exit {
         println!("--- begin frame ---");
         renderer.manager_mut().request_needed_tiles(&mut needed_tiles);
-        rasterize_needed_tiles(&device,
-                               &mut renderer,
-                               global_scale_factor,
-                               &mut cache_draw_target,
-                               &mut cache_pixels,
-                               &svg_tree,
-                               &mut needed_tiles);
+        let failed_tiles = rasterize_needed_tiles(&device,
+                                                  &mut renderer,
+                                                  &rasterizer,
+                                                  cache_channel_order,
+                                                  &mut cache_pixels,
+                                                  &mut needed_tiles);
+        for (tile_cache_entry, error) in failed_tiles {
+            println!("failed to rasterize {:?}: {:?}", tile_cache_entry, error);
+        }
 
         renderer.render(&device);
 
@@ -165,84 +198,226 @@ fn main() {
     }
 }
 
-fn rasterize_needed_tiles(device: &GLDevice,
-                          renderer: &mut SimpleRenderer<GLDevice>,
-                          global_scale_factor: f32,
-                          cache_draw_target: &mut DrawTarget,
-                          cache_pixels: &mut [u32],
-                          svg_tree: &Tree,
-                          needed_tiles: &mut Vec<TileCacheEntry>) {
-    if needed_tiles.is_empty() {
-        return;
+// Paints the layers in order, so later layers are drawn on top of earlier ones. Changing any
+// layer after tiles have been rasterized requires clearing the cache.
+struct SvgTileRasterizer {
+    layers: Vec<SvgLayer>,
+    svg_size: Vector2I,
+    global_scale_factor: f32,
+    // Tiles are rendered at this multiple of their size and box-filtered down, which smooths thin
+    // features at the cost of rasterization time.
+    supersample: u32,
+    // Chooses the background of each tile. Where it returns `None`, tiles are left transparent
+    // wherever no layer paints, for overlay content.
+    background_color: Box<dyn Fn(&TileDescriptor) -> Option<SolidSource> + Send>,
+}
+
+struct SvgLayer {
+    svg_tree: Tree,
+    svg_size: Vector2I,
+    // Maps this layer into the coordinate space of the first layer.
+    transform: Transform2F,
+}
+
+// The trees are never shared outside the rasterizer, so moving the whole thing to another thread
+// moves every reference-counted node along with it.
+unsafe impl Send for SvgTileRasterizer {}
+
+impl SvgTileRasterizer {
+    // The first layer determines the size of the content.
+    fn from_file(svg_path: &str, global_scale_factor: f32)
+                 -> Result<SvgTileRasterizer, RasterizeError> {
+        let layer = SvgLayer::from_file(svg_path, Transform2F::default())?;
+        let svg_size = layer.svg_size;
+        Ok(SvgTileRasterizer {
+            layers: vec![layer],
+            svg_size,
+            global_scale_factor,
+            supersample: 1,
+            background_color: Box::new(|_| Some(DEFAULT_BACKGROUND_COLOR)),
+        })
     }
 
-    let cache_texture_size = Vector2I::new(TILE_CACHE_WIDTH as i32, TILE_CACHE_HEIGHT as i32);
+    fn add_layer(&mut self, svg_path: &str, transform: Transform2F)
+                 -> Result<(), RasterizeError> {
+        self.layers.push(SvgLayer::from_file(svg_path, transform)?);
+        Ok(())
+    }
 
-    let svg_size = svg_tree.svg_node().size;
-    let svg_size = Vector2I::new(svg_size.width().ceil() as i32, svg_size.height().ceil() as i32);
-    let svg_screen_size = ScreenSize::new(svg_size.x() as u32, svg_size.y() as u32).unwrap();
+    fn set_supersample(&mut self, supersample: u32) {
+        assert!(supersample > 0, "The supersampling factor must be at least 1!");
+        self.supersample = supersample;
+    }
 
-    let tile_size = renderer.manager_mut().texture.tile_size();
+    fn set_background_color<F>(&mut self, background_color: F)
+                               where F: Fn(&TileDescriptor) -> Option<SolidSource> +
+                                        Send +
+                                        'static {
+        self.background_color = Box::new(background_color);
+    }
+}
+
+impl SvgLayer {
+    fn from_file(svg_path: &str, transform: Transform2F) -> Result<SvgLayer, RasterizeError> {
+        let svg_tree = match Tree::from_file(svg_path, &UsvgOptions::default()) {
+            Ok(svg_tree) => svg_tree,
+            Err(_) => return Err(RasterizeError::ParseFailed),
+        };
+        let svg_size = svg_tree.svg_node().size;
+        let svg_size = Vector2I::new(svg_size.width().ceil() as i32,
+                                     svg_size.height().ceil() as i32);
+        Ok(SvgLayer { svg_tree, svg_size, transform })
+    }
+}
+
+impl TileRasterizer for SvgTileRasterizer {
+    fn rasterize(&self, descriptor: TileDescriptor, tile_size: u32, border: u32)
+                 -> Result<Vec<u8>, RasterizeError> {
+        let tile_backing_size = (tile_size + border * 2) as i32;
+        let supersampled_size = tile_backing_size * self.supersample as i32;
+        let mut draw_target = DrawTarget::new(supersampled_size, supersampled_size);
 
-    for tile_cache_entry in needed_tiles.drain(..) {
-        println!("rendering {:?}, tile_size={}", tile_cache_entry, tile_size);
-        let descriptor = &tile_cache_entry.descriptor;
         let scene_offset =
             Vector2F::new(descriptor.x as f32, descriptor.y as f32).scale(-(tile_size as f32));
-        let scale = (1 << descriptor.lod) as f32 * global_scale_factor;
+        let scale = f32::exp2(descriptor.lod as f32) * self.global_scale_factor;
 
         let mut transform = Transform2F::default();
         transform = Transform2F::from_uniform_scale(scale) * transform;
         transform = Transform2F::from_translation(scene_offset) * transform;
-        transform = Transform2F::from_translation(Vector2F::splat(1.0)) * transform;
-        //transform = Transform2F::from_translation(tile_offset.to_f32()) * transform;
+        transform = Transform2F::from_translation(Vector2F::splat(border as f32)) * transform;
+        transform = Transform2F::from_uniform_scale(self.supersample as f32) * transform;
 
         println!("... transform={:?}", transform);
-        cache_draw_target.set_transform(&Transform::row_major(transform.matrix.m11(),
-                                                              transform.matrix.m21(),
-                                                              transform.matrix.m12(),
-                                                              transform.matrix.m22(),
-                                                              transform.vector.x(),
-                                                              transform.vector.y()));
-        cache_draw_target.clear(BACKGROUND_COLOR);
-        backend_raqote::render_to_canvas(&svg_tree,
-                                         &ResvgOptions::default(),
-                                         svg_screen_size,
-                                         cache_draw_target);
-        cache_draw_target.set_transform(&Transform::identity());
+        if let Some(background_color) = (self.background_color)(&descriptor) {
+            draw_target.clear(background_color);
+        }
+        for layer in &self.layers {
+            let svg_screen_size = match ScreenSize::new(layer.svg_size.x() as u32,
+                                                        layer.svg_size.y() as u32) {
+                None => return Err(RasterizeError::RenderFailed),
+                Some(svg_screen_size) => svg_screen_size,
+            };
+
+            let layer_transform = transform * layer.transform;
+            draw_target.set_transform(&Transform::row_major(layer_transform.matrix.m11(),
+                                                            layer_transform.matrix.m21(),
+                                                            layer_transform.matrix.m12(),
+                                                            layer_transform.matrix.m22(),
+                                                            layer_transform.vector.x(),
+                                                            layer_transform.vector.y()));
+            backend_raqote::render_to_canvas(&layer.svg_tree,
+                                             &ResvgOptions::default(),
+                                             svg_screen_size,
+                                             &mut draw_target);
+        }
+
+        let mut pixels = Vec::with_capacity(draw_target.get_data().len() * 4);
+        for &pixel in draw_target.get_data() {
+            pixels.extend_from_slice(&pixel.to_le_bytes());
+        }
+        if self.supersample > 1 {
+            pixels = downsample(&pixels, tile_backing_size as usize, self.supersample as usize);
+        }
+        Ok(pixels)
+    }
+
+    // raqote's 0xAARRGGBB words are written out little-endian above.
+    fn channel_order(&self) -> ChannelOrder {
+        ChannelOrder::BGRA
+    }
+
+    fn content_size(&self) -> Vector2I {
+        self.svg_size
+    }
+}
+
+fn rasterize_needed_tiles<R>(device: &GLDevice,
+                             renderer: &mut SimpleRenderer<GLDevice>,
+                             rasterizer: &R,
+                             cache_channel_order: ChannelOrder,
+                             cache_pixels: &mut [u8],
+                             needed_tiles: &mut Vec<TileCacheEntry>)
+                             -> Vec<(TileCacheEntry, RasterizeError)>
+                             where R: TileRasterizer {
+    let mut failed_tiles = vec![];
+    if needed_tiles.is_empty() {
+        return failed_tiles;
+    }
+
+    let cache_texture_size = Vector2I::new(TILE_CACHE_WIDTH as i32, TILE_CACHE_HEIGHT as i32);
+
+    let tile_size = renderer.manager_mut().texture.tile_size();
+    let tile_border = renderer.manager_mut().texture.tile_border();
+    let tile_backing_size = renderer.manager_mut().texture.tile_backing_size();
+    let bytes_per_pixel = renderer.cache_bytes_per_pixel() as usize;
+
+    for tile_cache_entry in needed_tiles.drain(..) {
+        println!("rendering {:?}, tile_size={}", tile_cache_entry, tile_size);
+        let mut tile_pixels =
+            match rasterizer.rasterize(tile_cache_entry.descriptor, tile_size, tile_border) {
+                Ok(tile_pixels) => tile_pixels,
+                Err(error) => {
+                    // Free the slot so that the tile is retried next frame.
+                    renderer.manager_mut().texture.invalidate(&tile_cache_entry.descriptor);
+                    failed_tiles.push((tile_cache_entry, error));
+                    continue;
+                }
+            };
+        swizzle_pixels(&mut tile_pixels, rasterizer.channel_order(), cache_channel_order);
 
         let address = tile_cache_entry.address;
-        let tile_rect = RectI::new(address.0, Vector2I::splat(1)).scale(TILE_BACKING_SIZE as i32);
+        let tile_rect = RectI::new(address.0, Vector2I::splat(1)).scale(tile_backing_size as i32);
 
         blit(cache_pixels,
              cache_texture_size.x() as usize,
              tile_rect,
-             cache_draw_target.get_data(),
-             TILE_BACKING_SIZE as usize,
-             Vector2I::default());
-    }
-    //cache_draw_target.write_png("cache.png").unwrap();
-    unsafe {
-        let cache_pixels: &[u8] = slice::from_raw_parts(cache_pixels.as_ptr() as *const u8,
-                                                        cache_pixels.len() * 4);
-        device.upload_to_texture(&renderer.cache_texture(), cache_texture_size, cache_pixels);
+             &tile_pixels,
+             tile_backing_size as usize,
+             Vector2I::default(),
+             bytes_per_pixel);
     }
+    device.upload_to_texture(renderer.cache_texture(device), cache_texture_size, cache_pixels);
+    failed_tiles
 }
 
-fn blit(dest: &mut [u32],
+fn blit(dest: &mut [u8],
         dest_stride: usize,
         dest_rect: RectI,
-        src: &[u32],
+        src: &[u8],
         src_stride: usize,
-        src_origin: Vector2I) {
+        src_origin: Vector2I,
+        bytes_per_pixel: usize) {
+    let row_length = dest_rect.size().x() as usize * bytes_per_pixel;
     for y in 0..dest_rect.size().y() {
-        let dest_start = (dest_rect.origin().y() + y) as usize * dest_stride +
-            dest_rect.origin().x() as usize;
-        let src_start = (src_origin.y() + y) as usize * src_stride + src_origin.x() as usize;
-        for x in 0..dest_rect.size().x() {
-            let pixel = src[src_start + x as usize];
-            dest[dest_start + x as usize] =
-                (pixel & 0x00ff00ff).rotate_right(16) | (pixel & 0xff00ff00);
+        let dest_start = ((dest_rect.origin().y() + y) as usize * dest_stride +
+            dest_rect.origin().x() as usize) * bytes_per_pixel;
+        let src_start = ((src_origin.y() + y) as usize * src_stride + src_origin.x() as usize) *
+            bytes_per_pixel;
+        dest[dest_start..(dest_start + row_length)]
+            .copy_from_slice(&src[src_start..(src_start + row_length)]);
+    }
+}
+
+// Box-filters 8-bit four-channel pixels, `size * factor` pixels across and down, to `size`.
+fn downsample(src: &[u8], size: usize, factor: usize) -> Vec<u8> {
+    let src_stride = size * factor;
+    let mut dest = Vec::with_capacity(size * size * 4);
+    for y in 0..size {
+        for x in 0..size {
+            let mut sum = [0u32; 4];
+            for sample_y in (y * factor)..((y + 1) * factor) {
+                for sample_x in (x * factor)..((x + 1) * factor) {
+                    let start = (sample_y * src_stride + sample_x) * 4;
+                    for (channel, value) in sum.iter_mut().enumerate() {
+                        *value += src[start + channel] as u32;
+                    }
+                }
+            }
+            let sample_count = (factor * factor) as u32;
+            for value in &sum {
+                dest.push(((value + sample_count / 2) / sample_count) as u8);
+            }
         }
     }
-}
\ No newline at end of file
+    dest
+}
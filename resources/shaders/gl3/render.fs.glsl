@@ -3,12 +3,12 @@
 // virtex/resources/shaders/render.fs.glsl
 
 uniform sampler2D uTileCache;
-uniform float uOpacity;
 
 in vec2 vTexCoord;
+in float vOpacity;
 
 out vec4 cFragColor;
 
 void main() {
-    cFragColor = texture(uTileCache, vTexCoord) * uOpacity;
+    cFragColor = texture(uTileCache, vTexCoord) * vOpacity;
 }
@@ -1,17 +1,35 @@
 // virtex/src/lib.rs
 
+use log::warn;
+use pathfinder_geometry::rect::RectI;
 use pathfinder_geometry::vector::Vector2I;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::collections::hash_map::HashMap;
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::mem;
+use std::ops::RangeInclusive;
 
 pub mod manager2d;
+pub mod rasterizer;
 pub mod renderer_simple;
+pub mod renderer_software;
+pub mod serialize;
 
-#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Debug)]
+pub const MIN_LOD: i32 = -32;
+pub const MAX_LOD: i32 = 31;
+
+const DEFAULT_TILE_SIZE: u32 = 256;
+const DEFAULT_TILE_BORDER: u32 = 1;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Hash, Debug, Serialize, Deserialize)]
 pub struct TileDescriptor {
     pub x: i32,
     pub y: i32,
     pub lod: i32,
+    /// Distinguishes independent images that share one cache, such as the frames of a flipbook.
+    pub layer: u8,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -26,13 +44,62 @@ pub struct TileCacheEntry {
 pub struct VirtualTexture {
     cache: HashMap<TileDescriptor, TileAddress>,
     lru: VecDeque<TileDescriptor>,
+    // Maps each prefetched tile to the frame in which it was last prefetched or touched.
+    prefetched_tiles: HashMap<TileDescriptor, u32>,
     free_tile_addresses: Vec<TileAddress>,
-    #[allow(dead_code)]
+    evicted_this_frame: Vec<TileDescriptor>,
+    cold_tiles: HashMap<TileDescriptor, TileAddress>,
+    lod_retention_weight: f32,
+    frame_index: u32,
+    virtual_texture_size: Vector2I,
+    cache_texture_size: Vector2I,
+    tile_size: u32,
+    tile_border: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VirtualTextureBuilder {
     virtual_texture_size: Vector2I,
     cache_texture_size: Vector2I,
     tile_size: u32,
+    border: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VirtualTextureError {
+    /// The cache texture can't hold even a single tile.
+    CacheTooSmall { needed: Vector2I, got: Vector2I },
+    /// Tiles must halve cleanly from one LOD to the next, or seams appear between them.
+    TileSizeNotPowerOfTwo(u32),
+}
+
+impl fmt::Display for VirtualTextureError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VirtualTextureError::CacheTooSmall { needed, got } => {
+                write!(formatter,
+                       "cache texture of {}x{} pixels can't hold a single {}x{} tile",
+                       got.x(),
+                       got.y(),
+                       needed.x(),
+                       needed.y())
+            }
+            VirtualTextureError::TileSizeNotPowerOfTwo(tile_size) => {
+                write!(formatter, "tile size {} isn't a power of two", tile_size)
+            }
+        }
+    }
 }
 
+impl Error for VirtualTextureError {}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TileCacheStatus {
+    Empty,
+    Resident(TileAddress),
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum RequestResult {
     CacheFull,
     CacheHit(TileAddress),
@@ -40,67 +107,467 @@ pub enum RequestResult {
 }
 
 impl VirtualTexture {
+    #[deprecated(note = "use `VirtualTextureBuilder`, whose named setters can't be swapped")]
     pub fn new(virtual_texture_size: Vector2I, cache_texture_size: Vector2I, tile_size: u32)
-               -> VirtualTexture {
+               -> Result<VirtualTexture, VirtualTextureError> {
+        VirtualTextureBuilder::new().virtual_texture_size(virtual_texture_size)
+                                    .cache_texture_size(cache_texture_size)
+                                    .tile_size(tile_size)
+                                    .build()
+    }
+
+    /// Like `new`, but doesn't check that the cache can hold any tiles. If it can't, every
+    /// request will return `CacheFull`.
+    pub fn new_unchecked(virtual_texture_size: Vector2I,
+                         cache_texture_size: Vector2I,
+                         tile_size: u32)
+                         -> VirtualTexture {
+        VirtualTexture::with_geometry(virtual_texture_size,
+                                      cache_texture_size,
+                                      tile_size,
+                                      DEFAULT_TILE_BORDER)
+    }
+
+    fn with_geometry(virtual_texture_size: Vector2I,
+                     cache_texture_size: Vector2I,
+                     tile_size: u32,
+                     tile_border: u32)
+                     -> VirtualTexture {
+        // Size everything for a full cache up front so we don't reallocate as it fills.
+        let tile_backing_size = tile_size + tile_border * 2;
+        let capacity = (cache_texture_size.x() as u32 / tile_backing_size) as usize *
+            (cache_texture_size.y() as u32 / tile_backing_size) as usize;
         let mut this = VirtualTexture {
-            cache: HashMap::new(),
-            lru: VecDeque::new(),
-            free_tile_addresses: vec![],
+            cache: HashMap::with_capacity(capacity),
+            lru: VecDeque::with_capacity(capacity),
+            prefetched_tiles: HashMap::new(),
+            free_tile_addresses: Vec::with_capacity(capacity),
+            evicted_this_frame: vec![],
+            cold_tiles: HashMap::new(),
+            lod_retention_weight: 0.0,
+            frame_index: 0,
             virtual_texture_size,
             cache_texture_size,
             tile_size,
+            tile_border,
         };
+        this.reset_free_tile_addresses();
+        this
+    }
+
+    /// Empties the cache, making every tile slot free.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+        self.lru.clear();
+        self.prefetched_tiles.clear();
+        self.cold_tiles.clear();
+        self.reset_free_tile_addresses();
+    }
+
+    /// Like `clear`, but remembers where each tile was. A later request for one of those tiles
+    /// returns `CacheHit` with its old address, so it needn't be rasterized again, as long as the
+    /// cache texture itself hasn't been touched in the meantime.
+    ///
+    /// Allocating a slot to any other tile forgets the tile that used to occupy it.
+    pub fn soft_clear(&mut self) {
+        let cache = mem::take(&mut self.cache);
+        self.cold_tiles.extend(cache);
+        self.lru.clear();
+        self.prefetched_tiles.clear();
+        self.reset_free_tile_addresses();
+    }
 
-        let tiles_down = this.tile_texture_tiles_down() as i32;
-        let tiles_across = this.tile_texture_tiles_across() as i32;
+    /// Switches to a different tile size, emptying the cache. Every descriptor from before the
+    /// change refers to a different region of the content afterward, so callers must forget them.
+    ///
+    /// The cache texture keeps its size, so renderers need not reallocate it. On error, nothing
+    /// changes.
+    pub fn resize_tiles(&mut self, new_tile_size: u32) -> Result<(), VirtualTextureError> {
+        if !new_tile_size.is_power_of_two() {
+            return Err(VirtualTextureError::TileSizeNotPowerOfTwo(new_tile_size));
+        }
+        let new_tile_backing_size = new_tile_size + self.tile_border * 2;
+        if self.cache_texture_size.x() < new_tile_backing_size as i32 ||
+                self.cache_texture_size.y() < new_tile_backing_size as i32 {
+            return Err(VirtualTextureError::CacheTooSmall {
+                needed: Vector2I::splat(new_tile_backing_size as i32),
+                got: self.cache_texture_size,
+            });
+        }
+
+        self.tile_size = new_tile_size;
+        self.clear();
+        Ok(())
+    }
+
+    fn reset_free_tile_addresses(&mut self) {
+        self.free_tile_addresses.clear();
+        let tiles_down = self.tile_texture_tiles_down() as i32;
+        let tiles_across = self.tile_texture_tiles_across() as i32;
         for tile_y in 0..tiles_down {
             for tile_x in 0..tiles_across {
-                this.free_tile_addresses.push(TileAddress(Vector2I::new(tile_x, tile_y)));
+                self.free_tile_addresses.push(TileAddress(Vector2I::new(tile_x, tile_y)));
             }
         }
+    }
 
-        this
+    /// Starts a new frame, forgetting the previous frame's evictions. A prefetch may only evict
+    /// tiles that were prefetched, and not touched since, in an earlier frame.
+    /// `VirtualTextureManager2D` calls this for you.
+    #[inline]
+    pub fn begin_frame(&mut self) {
+        self.frame_index = self.frame_index.wrapping_add(1);
+        self.evicted_this_frame.clear();
     }
 
     pub fn request_tile(&mut self, tile_descriptor: &TileDescriptor) -> RequestResult {
-        if let Some(&tile_address) = self.cache.get(tile_descriptor) {
-            let lru_index = self.lru.iter().enumerate().find(|(_, current_descriptor)| {
-                *current_descriptor == tile_descriptor
-            }).expect("Where's the descriptor in the LRU list?").0;
-            self.lru.remove(lru_index);
-            self.lru.push_front(*tile_descriptor);
+        let result = self.allocate_tile(tile_descriptor, false);
+        self.prefetched_tiles.remove(tile_descriptor);
+        result
+    }
+
+    /// Like `request_tile`, but for a tile that isn't needed yet and is only being cached
+    /// speculatively.
+    ///
+    /// When the cache is full, a request evicts tiles in this order:
+    ///
+    /// 1. Prefetched tiles that haven't since been requested, least recently used first.
+    ///
+    /// 2. All other tiles, least recently used first, adjusted by `set_lod_retention_weight`.
+    ///
+    /// A prefetch, though, only ever evicts prefetched tiles from earlier frames (see
+    /// `begin_frame`), so that it can't push out a tile that's on screen. If there are none, it
+    /// returns `CacheFull`.
+    ///
+    /// A prefetched tile becomes an ordinary tile as soon as it's requested with `request_tile`.
+    pub fn prefetch_tile(&mut self, tile_descriptor: &TileDescriptor) -> RequestResult {
+        let result = self.allocate_tile(tile_descriptor, true);
+        if let RequestResult::CacheMiss(_) = result {
+            self.prefetched_tiles.insert(*tile_descriptor, self.frame_index);
+        }
+        result
+    }
+
+    /// Marks a resident tile as most recently used, returning its address, or `None` if the tile
+    /// isn't resident. Unlike `request_tile`, this never allocates, and a prefetched tile stays
+    /// prefetched.
+    pub fn touch_tile(&mut self, tile_descriptor: &TileDescriptor) -> Option<TileAddress> {
+        let tile_address = *self.cache.get(tile_descriptor)?;
+
+        // Every cached descriptor should be in the LRU list, but if that's ever violated, repair
+        // the list rather than crashing.
+        match self.lru.iter().position(|current_descriptor| current_descriptor == tile_descriptor) {
+            Some(lru_index) => {
+                self.lru.remove(lru_index);
+            }
+            None => {
+                warn!("{:?} was cached but missing from the LRU list", tile_descriptor);
+            }
+        }
+        self.lru.push_front(*tile_descriptor);
+        if let Some(frame_index) = self.prefetched_tiles.get_mut(tile_descriptor) {
+            *frame_index = self.frame_index;
+        }
+        Some(tile_address)
+    }
+
+    fn allocate_tile(&mut self, tile_descriptor: &TileDescriptor, prefetch: bool)
+                     -> RequestResult {
+        if let Some(tile_address) = self.touch_tile(tile_descriptor) {
             return RequestResult::CacheHit(tile_address);
         }
 
-        if self.free_tile_addresses.is_empty() {
-            let descriptor_to_evict = match self.lru.pop_back() {
-                None => return RequestResult::CacheFull,
-                Some(descriptor_to_evict) => descriptor_to_evict,
-            };
-            let tile_address_to_evict =
-                self.cache
-                    .remove(&descriptor_to_evict)
-                    .expect("Where's the descriptor in the cache?");
-            self.free_tile_addresses.push(tile_address_to_evict);
+        if let Some(tile_address) = self.cold_tiles.remove(tile_descriptor) {
+            // The slot can't have been reused, or the entry would have been dropped.
+            self.free_tile_addresses.retain(|free_address| free_address.0 != tile_address.0);
+            self.cache.insert(*tile_descriptor, tile_address);
+            self.lru.push_front(*tile_descriptor);
+            return RequestResult::CacheHit(tile_address);
         }
 
-        let tile_address = match self.free_tile_addresses.pop() {
+        let tile_address = match self.get_next_free_tile(prefetch) {
             None => return RequestResult::CacheFull,
             Some(tile_address) => tile_address,
         };
+        if !self.cold_tiles.is_empty() {
+            self.cold_tiles.retain(|_, cold_address| cold_address.0 != tile_address.0);
+        }
         self.cache.insert(*tile_descriptor, tile_address);
         self.lru.push_front(*tile_descriptor);
         RequestResult::CacheMiss(tile_address)
     }
 
+    fn get_next_free_tile(&mut self, prefetch: bool) -> Option<TileAddress> {
+        while self.free_tile_addresses.is_empty() {
+            let descriptor_to_evict = if prefetch {
+                self.choose_prefetched_tile_to_evict()?
+            } else {
+                self.choose_tile_to_evict()?
+            };
+            self.prefetched_tiles.remove(&descriptor_to_evict);
+            // The LRU list may hold stale entries that are no longer in the cache. Skip them.
+            if let Some(tile_address_to_evict) = self.cache.remove(&descriptor_to_evict) {
+                self.free_tile_addresses.push(tile_address_to_evict);
+                self.evicted_this_frame.push(descriptor_to_evict);
+            }
+        }
+        self.free_tile_addresses.pop()
+    }
+
+    // Removes the next tile to be evicted from the LRU list. See `prefetch_tile` for the order.
+    fn choose_tile_to_evict(&mut self) -> Option<TileDescriptor> {
+        if !self.prefetched_tiles.is_empty() {
+            let prefetched_tiles = &self.prefetched_tiles;
+            if let Some(lru_index) = self.lru.iter().rposition(|descriptor| {
+                prefetched_tiles.contains_key(descriptor)
+            }) {
+                return self.lru.remove(lru_index);
+            }
+        }
+        if self.lod_retention_weight == 0.0 {
+            return self.lru.pop_back();
+        }
+
+        // Evict the tile with the highest score, preferring the least recently used on ties.
+        let lod_retention_weight = self.lod_retention_weight;
+        let mut best: Option<(usize, f32)> = None;
+        for (lru_index, descriptor) in self.lru.iter().enumerate() {
+            let score = lru_index as f32 + lod_retention_weight * descriptor.lod as f32;
+            match best {
+                Some((_, best_score)) if score < best_score => {}
+                _ => best = Some((lru_index, score)),
+            }
+        }
+        self.lru.remove(best?.0)
+    }
+
+    // Like `choose_tile_to_evict`, but only considers tiles prefetched before this frame.
+    fn choose_prefetched_tile_to_evict(&mut self) -> Option<TileDescriptor> {
+        let (prefetched_tiles, frame_index) = (&self.prefetched_tiles, self.frame_index);
+        let lru_index = self.lru.iter().rposition(|descriptor| {
+            match prefetched_tiles.get(descriptor) {
+                None => false,
+                Some(&prefetch_frame_index) => prefetch_frame_index != frame_index,
+            }
+        })?;
+        self.lru.remove(lru_index)
+    }
+
+    /// How strongly eviction favors keeping coarse tiles, which cover more of the content and are
+    /// costlier to lose. Each LOD coarser counts the same as being `weight` places more recently
+    /// used. The default, zero, evicts in pure LRU order.
+    ///
+    /// Prefetched tiles are still evicted before any others.
+    #[inline]
+    pub fn set_lod_retention_weight(&mut self, weight: f32) {
+        self.lod_retention_weight = weight;
+    }
+
+    #[inline]
+    pub fn lod_retention_weight(&self) -> f32 {
+        self.lod_retention_weight
+    }
+
+    /// Removes a tile from the cache and frees its slot, so that the next request for it misses.
+    /// Call this when a tile couldn't be rasterized, so that it's retried instead of showing
+    /// whatever the slot held before. Returns false if the tile wasn't resident.
+    pub fn invalidate(&mut self, tile_descriptor: &TileDescriptor) -> bool {
+        self.prefetched_tiles.remove(tile_descriptor);
+        let tile_address = match self.cache.remove(tile_descriptor) {
+            None => return false,
+            Some(tile_address) => tile_address,
+        };
+        self.lru.retain(|descriptor| descriptor != tile_descriptor);
+        self.free_tile_addresses.push(tile_address);
+        true
+    }
+
+    /// Places a tile in the given slot, bypassing the allocator, and marks it most recently used.
+    /// This is meant for tests that need tiles at known addresses.
+    ///
+    /// Any other tile occupying the slot is evicted and reported by `take_evicted`. If the tile
+    /// was already resident elsewhere, its old slot is freed.
+    pub fn force_insert(&mut self, descriptor: TileDescriptor, address: TileAddress) {
+        self.prefetched_tiles.remove(&descriptor);
+        if let Some(old_address) = self.cache.get(&descriptor) {
+            if old_address.0 == address.0 {
+                self.touch_tile(&descriptor);
+                return;
+            }
+        }
+
+        let occupant = self.cache.iter().find(|(_, cached_address)| {
+            cached_address.0 == address.0
+        }).map(|(&occupant, _)| occupant);
+        match occupant {
+            Some(occupant) => {
+                self.cache.remove(&occupant);
+                self.prefetched_tiles.remove(&occupant);
+                self.lru.retain(|lru_descriptor| *lru_descriptor != occupant);
+                self.evicted_this_frame.push(occupant);
+            }
+            None => self.free_tile_addresses.retain(|free_address| free_address.0 != address.0),
+        }
+        if !self.cold_tiles.is_empty() {
+            self.cold_tiles.retain(|_, cold_address| cold_address.0 != address.0);
+        }
+        self.cold_tiles.remove(&descriptor);
+
+        if let Some(old_address) = self.cache.insert(descriptor, address) {
+            self.free_tile_addresses.push(old_address);
+        }
+        self.lru.retain(|lru_descriptor| *lru_descriptor != descriptor);
+        self.lru.push_front(descriptor);
+    }
+
+    /// Evicts every resident tile whose LOD lies outside `keep`, freeing its slot. Evicted tiles
+    /// are reported by `take_evicted` as usual.
+    pub fn evict_lods_outside(&mut self, keep: RangeInclusive<i32>) {
+        let cache = &mut self.cache;
+        let prefetched_tiles = &mut self.prefetched_tiles;
+        let free_tile_addresses = &mut self.free_tile_addresses;
+        let evicted_this_frame = &mut self.evicted_this_frame;
+        self.lru.retain(|descriptor| {
+            if keep.contains(&descriptor.lod) {
+                return true;
+            }
+            if let Some(tile_address) = cache.remove(descriptor) {
+                free_tile_addresses.push(tile_address);
+                evicted_this_frame.push(*descriptor);
+            }
+            prefetched_tiles.remove(descriptor);
+            false
+        });
+    }
+
+    /// Looks up a tile without affecting its position in the LRU list.
+    #[inline]
+    pub fn tile_status(&self, tile_descriptor: &TileDescriptor) -> TileCacheStatus {
+        match self.cache.get(tile_descriptor) {
+            None => TileCacheStatus::Empty,
+            Some(&tile_address) => TileCacheStatus::Resident(tile_address),
+        }
+    }
+
+    /// Returns the tile itself if it's resident, or else its nearest resident ancestor at most
+    /// `max_levels` LODs coarser. Each coarser LOD halves the tile coordinates.
+    pub fn nearest_resident_ancestor(&self, tile_descriptor: &TileDescriptor, max_levels: u8)
+                                     -> Option<(TileDescriptor, TileAddress)> {
+        let mut descriptor = *tile_descriptor;
+        for level in 0..=max_levels {
+            if level > 0 {
+                if descriptor.lod == MIN_LOD {
+                    break;
+                }
+                descriptor = TileDescriptor {
+                    x: descriptor.x >> 1,
+                    y: descriptor.y >> 1,
+                    lod: descriptor.lod - 1,
+                    layer: descriptor.layer,
+                };
+            }
+            if let Some(&tile_address) = self.cache.get(&descriptor) {
+                return Some((descriptor, tile_address));
+            }
+        }
+        None
+    }
+
+    /// Returns the descriptors evicted since the last call or the start of the frame, whichever
+    /// came later, in eviction order. Callers that care should call it before the next
+    /// `begin_frame`.
+    pub fn take_evicted(&mut self) -> Vec<TileDescriptor> {
+        mem::take(&mut self.evicted_this_frame)
+    }
+
+    #[inline]
+    pub fn resident_tile_count(&self) -> u32 {
+        self.cache.len() as u32
+    }
+
+    /// The total number of tiles the cache texture can hold.
+    #[inline]
+    pub fn capacity(&self) -> u32 {
+        self.tile_texture_tiles_across() * self.tile_texture_tiles_down()
+    }
+
+    /// Returns true if no tile slots are free. The next cache miss will then evict the least
+    /// recently used tile, or return `CacheFull` if there's nothing to evict.
+    #[inline]
+    pub fn is_full(&self) -> bool {
+        self.free_tile_addresses.is_empty()
+    }
+
+    /// The number of cache texture pixels that no tile slot covers, because the cache texture size
+    /// isn't a multiple of the tile backing size.
+    pub fn wasted_atlas_pixels(&self) -> u64 {
+        let tile_backing_size = self.tile_backing_size() as u64;
+        let used_pixels = self.capacity() as u64 * tile_backing_size * tile_backing_size;
+        let total_pixels = self.cache_texture_size.x() as u64 * self.cache_texture_size.y() as u64;
+        total_pixels - used_pixels
+    }
+
+    /// An estimate of the memory that the CPU-side bookkeeping occupies, in bytes. This doesn't
+    /// include the cache texture itself.
+    pub fn metadata_bytes(&self) -> u64 {
+        let descriptor_size = mem::size_of::<TileDescriptor>();
+        let address_size = mem::size_of::<TileAddress>();
+        let bytes = self.cache.capacity() * (descriptor_size + address_size) +
+            self.cold_tiles.capacity() * (descriptor_size + address_size) +
+            self.lru.capacity() * descriptor_size +
+            self.prefetched_tiles.capacity() * (descriptor_size + mem::size_of::<u32>()) +
+            self.evicted_this_frame.capacity() * descriptor_size +
+            self.free_tile_addresses.capacity() * address_size;
+        bytes as u64
+    }
+
+    /// Describes the contents of the cache, one resident tile per line, most recently used first.
+    /// This is slow and meant only for debugging.
+    pub fn dump(&self) -> String {
+        let mut output = String::new();
+        writeln!(output,
+                 "{} of {} tiles resident, {} prefetched, {} free, {} cold",
+                 self.resident_tile_count(),
+                 self.capacity(),
+                 self.prefetched_tiles.len(),
+                 self.free_tile_addresses.len(),
+                 self.cold_tiles.len()).unwrap();
+        for (lru_rank, descriptor) in self.lru.iter().enumerate() {
+            let address = match self.cache.get(descriptor) {
+                None => "(stale)".to_owned(),
+                Some(address) => format!("({}, {})", address.0.x(), address.0.y()),
+            };
+            let prefetched = if self.prefetched_tiles.contains_key(descriptor) {
+                " prefetched"
+            } else {
+                ""
+            };
+            writeln!(output, "{:>5} {:?} at {}{}", lru_rank, descriptor, address, prefetched)
+                .unwrap();
+        }
+        output
+    }
+
     #[inline]
     pub fn tile_size(&self) -> u32 {
         self.tile_size
     }
 
+    #[inline]
+    pub fn tile_border(&self) -> u32 {
+        self.tile_border
+    }
+
     #[inline]
     pub fn tile_backing_size(&self) -> u32 {
-        self.tile_size + 2
+        self.tile_size + self.tile_border() * 2
+    }
+
+    /// The size of the content at LOD 0, in pixels. Zero in either dimension means the size is
+    /// unknown.
+    #[inline]
+    pub fn virtual_texture_size(&self) -> Vector2I {
+        self.virtual_texture_size
     }
 
     #[inline]
@@ -108,6 +575,13 @@ impl VirtualTexture {
         self.cache_texture_size
     }
 
+    /// The rectangle of the cache texture, including the border, backing the tile at `address`.
+    #[inline]
+    pub(crate) fn tile_backing_rect(&self, address: TileAddress) -> RectI {
+        let tile_backing_size = self.tile_backing_size() as i32;
+        RectI::new(address.0.scale(tile_backing_size), Vector2I::splat(tile_backing_size))
+    }
+
     #[inline]
     fn tile_texture_tiles_across(&self) -> u32 {
         self.cache_texture_size.x() as u32 / self.tile_backing_size()
@@ -118,10 +592,442 @@ impl VirtualTexture {
         self.cache_texture_size.y() as u32 / self.tile_backing_size()
     }
 
-    pub fn all_cached_tiles(&self) -> Vec<TileCacheEntry> {
-        self.cache
-            .iter()
-            .map(|(&descriptor, &address)| TileCacheEntry { descriptor, address })
-            .collect()
+    /// The rectangle of the cache texture holding the given tile, with or without its border, or
+    /// `None` if the tile isn't resident.
+    pub fn tile_rect_in_cache(&self, tile_descriptor: &TileDescriptor, include_border: bool)
+                              -> Option<RectI> {
+        let tile_backing_rect = self.tile_backing_rect(*self.cache.get(tile_descriptor)?);
+        if include_border {
+            return Some(tile_backing_rect);
+        }
+        Some(RectI::new(tile_backing_rect.origin() + Vector2I::splat(self.tile_border as i32),
+                        Vector2I::splat(self.tile_size as i32)))
+    }
+
+    /// Iterates over every resident tile along with the rectangle of the cache texture backing
+    /// it, in pixels. The rectangle includes the tile border.
+    pub fn cached_tiles(&self) -> impl Iterator<Item = (TileDescriptor, TileAddress, RectI)> + '_ {
+        self.cache.iter().map(move |(&descriptor, &address)| {
+            (descriptor, address, self.tile_backing_rect(address))
+        })
+    }
+}
+
+impl Default for VirtualTextureBuilder {
+    #[inline]
+    fn default() -> VirtualTextureBuilder {
+        VirtualTextureBuilder {
+            virtual_texture_size: Vector2I::default(),
+            cache_texture_size: Vector2I::default(),
+            tile_size: DEFAULT_TILE_SIZE,
+            border: DEFAULT_TILE_BORDER,
+        }
+    }
+}
+
+impl VirtualTextureBuilder {
+    #[inline]
+    pub fn new() -> VirtualTextureBuilder {
+        VirtualTextureBuilder::default()
+    }
+
+    /// The size of the content at LOD 0, in pixels. Tiles outside it are never requested. If this
+    /// is left unset, every tile the viewport covers is requested.
+    #[inline]
+    pub fn virtual_texture_size(mut self, size: Vector2I) -> VirtualTextureBuilder {
+        self.virtual_texture_size = size;
+        self
+    }
+
+    #[inline]
+    pub fn cache_texture_size(mut self, size: Vector2I) -> VirtualTextureBuilder {
+        self.cache_texture_size = size;
+        self
+    }
+
+    #[inline]
+    pub fn tile_size(mut self, tile_size: u32) -> VirtualTextureBuilder {
+        self.tile_size = tile_size;
+        self
+    }
+
+    /// The number of pixels of padding around each tile in the cache texture, which keeps
+    /// bilinear filtering from bleeding in neighboring tiles.
+    #[inline]
+    pub fn border(mut self, border: u32) -> VirtualTextureBuilder {
+        self.border = border;
+        self
+    }
+
+    pub fn build(self) -> Result<VirtualTexture, VirtualTextureError> {
+        if !self.tile_size.is_power_of_two() {
+            return Err(VirtualTextureError::TileSizeNotPowerOfTwo(self.tile_size));
+        }
+
+        let texture = VirtualTexture::with_geometry(self.virtual_texture_size,
+                                                    self.cache_texture_size,
+                                                    self.tile_size,
+                                                    self.border);
+        if texture.capacity() == 0 {
+            return Err(VirtualTextureError::CacheTooSmall {
+                needed: Vector2I::splat(texture.tile_backing_size() as i32),
+                got: self.cache_texture_size,
+            });
+        }
+        if texture.wasted_atlas_pixels() > 0 {
+            warn!("cache texture size {:?} isn't a multiple of the tile backing size {}; {} \
+                   pixels will go unused",
+                  self.cache_texture_size,
+                  texture.tile_backing_size(),
+                  texture.wasted_atlas_pixels());
+        }
+        Ok(texture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture_with_capacity(tiles_across: i32, tiles_down: i32) -> VirtualTexture {
+        let tile_backing_size = (DEFAULT_TILE_SIZE + DEFAULT_TILE_BORDER * 2) as i32;
+        let cache_texture_size = Vector2I::new(tiles_across, tiles_down).scale(tile_backing_size);
+        VirtualTextureBuilder::new().cache_texture_size(cache_texture_size).build().unwrap()
+    }
+
+    fn tile(x: i32, lod: i32) -> TileDescriptor {
+        TileDescriptor { x, y: 0, lod, layer: 0 }
+    }
+
+    fn is_resident(texture: &VirtualTexture, descriptor: &TileDescriptor) -> bool {
+        match texture.tile_status(descriptor) {
+            TileCacheStatus::Resident(_) => true,
+            TileCacheStatus::Empty => false,
+        }
+    }
+
+    #[test]
+    fn test_invalidate_frees_slot_and_misses_next_time() {
+        let mut texture = texture_with_capacity(2, 1);
+        let address = match texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheMiss(address) => address,
+            result => panic!("expected a miss, got {:?}", result),
+        };
+        assert!(texture.invalidate(&tile(0, 0)));
+        assert!(!texture.invalidate(&tile(0, 0)));
+        assert!(!is_resident(&texture, &tile(0, 0)));
+        assert!(!texture.is_full());
+
+        match texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheMiss(new_address) => assert_eq!(new_address.0, address.0),
+            result => panic!("expected a miss, got {:?}", result),
+        }
+        assert_eq!(texture.resident_tile_count(), 1);
+    }
+
+    #[test]
+    fn test_insert_evict_reinsert() {
+        let mut texture = texture_with_capacity(2, 1);
+        texture.request_tile(&tile(0, 0));
+        texture.request_tile(&tile(1, 0));
+        texture.request_tile(&tile(2, 0));
+        assert_eq!(texture.take_evicted(), vec![tile(0, 0)]);
+
+        match texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheMiss(_) => {}
+            result => panic!("expected a miss, got {:?}", result),
+        }
+        assert_eq!(texture.take_evicted(), vec![tile(1, 0)]);
+        match texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheHit(_) => {}
+            result => panic!("expected a hit, got {:?}", result),
+        }
+        assert!(is_resident(&texture, &tile(2, 0)));
+        assert_eq!(texture.resident_tile_count(), 2);
+    }
+
+    #[test]
+    fn test_is_full_at_capacity() {
+        let mut texture = texture_with_capacity(2, 2);
+        assert_eq!(texture.capacity(), 4);
+        for x in 0..4 {
+            assert!(!texture.is_full());
+            texture.request_tile(&tile(x, 0));
+        }
+        assert!(texture.is_full());
+        assert_eq!(texture.resident_tile_count(), 4);
+
+        // A full cache evicts the least recently used tile rather than refusing the request.
+        match texture.request_tile(&tile(4, 0)) {
+            RequestResult::CacheMiss(_) => {}
+            result => panic!("expected a miss, got {:?}", result),
+        }
+        assert_eq!(texture.take_evicted(), vec![tile(0, 0)]);
+        assert!(texture.is_full());
+
+        // Only a cache with no slots at all refuses requests.
+        let mut empty_texture = VirtualTexture::new_unchecked(Vector2I::default(),
+                                                              Vector2I::splat(16),
+                                                              DEFAULT_TILE_SIZE);
+        assert!(empty_texture.is_full());
+        match empty_texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheFull => {}
+            result => panic!("expected a full cache, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_prefetch_only_evicts_prefetched_tiles_from_earlier_frames() {
+        let mut texture = texture_with_capacity(2, 1);
+        texture.request_tile(&tile(0, 0));
+        texture.prefetch_tile(&tile(1, 0));
+
+        // Neither the requested tile nor one prefetched this frame may be evicted.
+        match texture.prefetch_tile(&tile(2, 0)) {
+            RequestResult::CacheFull => {}
+            result => panic!("expected a full cache, got {:?}", result),
+        }
+        assert!(texture.take_evicted().is_empty());
+
+        // Touching a prefetched tile protects it for the rest of the frame, too.
+        texture.begin_frame();
+        texture.touch_tile(&tile(1, 0));
+        match texture.prefetch_tile(&tile(2, 0)) {
+            RequestResult::CacheFull => {}
+            result => panic!("expected a full cache, got {:?}", result),
+        }
+
+        texture.begin_frame();
+        match texture.prefetch_tile(&tile(2, 0)) {
+            RequestResult::CacheMiss(_) => {}
+            result => panic!("expected a miss, got {:?}", result),
+        }
+        assert_eq!(texture.take_evicted(), vec![tile(1, 0)]);
+        assert!(is_resident(&texture, &tile(0, 0)));
+    }
+
+    #[test]
+    fn test_force_insert_evicts_occupant_and_tolerates_reinsertion() {
+        let mut texture = texture_with_capacity(2, 1);
+        let (first, second) = (TileAddress(Vector2I::new(0, 0)), TileAddress(Vector2I::new(1, 0)));
+        texture.force_insert(tile(0, 0), first);
+        texture.force_insert(tile(0, 0), first);
+        assert_eq!(texture.resident_tile_count(), 1);
+        assert!(!texture.is_full());
+
+        texture.force_insert(tile(1, 0), first);
+        assert_eq!(texture.take_evicted(), vec![tile(0, 0)]);
+        assert!(!is_resident(&texture, &tile(0, 0)));
+        assert_eq!(texture.resident_tile_count(), 1);
+
+        // Moving a tile frees its old slot, and each slot is handed out only once.
+        texture.force_insert(tile(1, 0), second);
+        match texture.request_tile(&tile(2, 0)) {
+            RequestResult::CacheMiss(address) => assert_eq!(address.0, first.0),
+            result => panic!("expected a miss, got {:?}", result),
+        }
+        assert!(texture.is_full());
+        assert!(texture.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn test_evict_lods_outside_keeps_only_the_band() {
+        let mut texture = texture_with_capacity(3, 2);
+        for lod in -1..=3 {
+            texture.request_tile(&tile(0, lod));
+        }
+        texture.evict_lods_outside(0..=2);
+
+        for lod in -1..=3 {
+            assert_eq!(is_resident(&texture, &tile(0, lod)), (0..=2).contains(&lod), "LOD {}", lod);
+        }
+        let mut evicted = texture.take_evicted();
+        evicted.sort();
+        assert_eq!(evicted, vec![tile(0, -1), tile(0, 3)]);
+        assert_eq!(texture.resident_tile_count(), 3);
+    }
+
+    #[test]
+    fn test_tile_touched_every_frame_survives_churn() {
+        let mut texture = texture_with_capacity(2, 2);
+        let hot_tile = tile(-1, 0);
+        texture.request_tile(&hot_tile);
+        for frame in 0..20 {
+            assert!(texture.touch_tile(&hot_tile).is_some(), "frame {}", frame);
+            texture.request_tile(&tile(frame, 0));
+            texture.request_tile(&tile(frame, 1));
+            assert!(!texture.take_evicted().contains(&hot_tile), "frame {}", frame);
+        }
+        assert!(is_resident(&texture, &hot_tile));
+    }
+
+    #[test]
+    fn test_nearest_resident_ancestor_finds_coarser_tile() {
+        let mut texture = texture_with_capacity(2, 1);
+        let ancestor = TileDescriptor { x: 0, y: 1, lod: 0, layer: 0 };
+        let address = match texture.request_tile(&ancestor) {
+            RequestResult::CacheMiss(address) => address,
+            result => panic!("expected a miss, got {:?}", result),
+        };
+
+        let descendant = TileDescriptor { x: 3, y: 6, lod: 2, layer: 0 };
+        assert!(!is_resident(&texture, &descendant));
+        match texture.nearest_resident_ancestor(&descendant, 2) {
+            Some((found, found_address)) => {
+                assert_eq!(found, ancestor);
+                assert_eq!(found_address.0, address.0);
+            }
+            None => panic!("no ancestor found"),
+        }
+        assert!(texture.nearest_resident_ancestor(&descendant, 1).is_none());
+    }
+
+    #[test]
+    fn test_lod_retention_weight_keeps_coarse_tiles() {
+        // Whichever of the two was requested last, the coarse tile survives.
+        for &(first, second) in &[(tile(0, -2), tile(0, 2)), (tile(0, 2), tile(0, -2))] {
+            let mut texture = texture_with_capacity(2, 1);
+            texture.set_lod_retention_weight(1.0);
+            texture.request_tile(&first);
+            texture.request_tile(&second);
+            texture.request_tile(&tile(1, 0));
+            assert_eq!(texture.take_evicted(), vec![tile(0, 2)]);
+            assert!(is_resident(&texture, &tile(0, -2)));
+        }
+    }
+
+    #[test]
+    fn test_begin_frame_forgets_evictions() {
+        let mut texture = texture_with_capacity(1, 1);
+        texture.request_tile(&tile(0, 0));
+        texture.request_tile(&tile(1, 0));
+        texture.begin_frame();
+        assert!(texture.take_evicted().is_empty());
+
+        texture.request_tile(&tile(2, 0));
+        assert_eq!(texture.take_evicted(), vec![tile(1, 0)]);
+        assert!(texture.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn test_tall_narrow_atlas_addresses_are_distinct_and_in_bounds() {
+        let mut texture = texture_with_capacity(1, 256);
+        assert_eq!(texture.capacity(), 256);
+        assert_eq!(texture.wasted_atlas_pixels(), 0);
+
+        let cache_texture_size = texture.cache_texture_size();
+        let mut tile_rects = vec![];
+        for x in 0..256 {
+            let address = match texture.request_tile(&tile(x, 0)) {
+                RequestResult::CacheMiss(address) => address,
+                result => panic!("expected a miss, got {:?}", result),
+            };
+            let tile_rect = texture.tile_backing_rect(address);
+            assert!(tile_rect.min_x() >= 0 && tile_rect.max_x() <= cache_texture_size.x() &&
+                        tile_rect.min_y() >= 0 && tile_rect.max_y() <= cache_texture_size.y(),
+                    "{:?} is outside the cache",
+                    tile_rect);
+            tile_rects.push((tile_rect.origin().x(), tile_rect.origin().y()));
+        }
+        tile_rects.sort();
+        tile_rects.dedup();
+        assert_eq!(tile_rects.len(), 256);
+        assert!(texture.is_full());
+    }
+
+    #[test]
+    fn test_wasted_atlas_pixels_counts_remainder() {
+        // Only 1x2 tiles of 258x258 pixels fit; the rest of the 300x600 texture is wasted.
+        let texture = VirtualTextureBuilder::new().cache_texture_size(Vector2I::new(300, 600))
+                                                  .build()
+                                                  .unwrap();
+        assert_eq!(texture.capacity(), 2);
+        assert_eq!(texture.wasted_atlas_pixels(), 300 * 600 - 2 * 258 * 258);
+    }
+
+    fn expect_miss(result: RequestResult) -> TileAddress {
+        match result {
+            RequestResult::CacheMiss(address) => address,
+            result => panic!("expected a miss, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_soft_clear_keeps_tiles_until_their_slots_are_reused() {
+        let mut texture = texture_with_capacity(2, 1);
+        let first_address = expect_miss(texture.request_tile(&tile(0, 0)));
+        expect_miss(texture.request_tile(&tile(1, 0)));
+        texture.soft_clear();
+        assert_eq!(texture.resident_tile_count(), 0);
+
+        // Promoting a cold tile takes its old slot off the free list...
+        match texture.request_tile(&tile(0, 0)) {
+            RequestResult::CacheHit(address) => assert_eq!(address.0, first_address.0),
+            result => panic!("expected a hit, got {:?}", result),
+        }
+        // ...so the next miss reuses the other tile's slot, forgetting that tile.
+        let second_address = expect_miss(texture.request_tile(&tile(2, 0)));
+        assert_ne!(second_address.0, first_address.0);
+        expect_miss(texture.request_tile(&tile(1, 0)));
+
+        // Forcing a tile into a cold tile's slot forgets the cold tile too.
+        texture.soft_clear();
+        texture.force_insert(tile(3, 0), second_address);
+        expect_miss(texture.request_tile(&tile(2, 0)));
+    }
+
+    #[test]
+    fn test_resize_tiles() {
+        let mut texture = texture_with_capacity(2, 2);
+        texture.request_tile(&tile(0, 0));
+
+        // Rejected sizes change nothing.
+        let too_big = Vector2I::splat(DEFAULT_TILE_SIZE as i32 * 4 + 2);
+        assert_eq!(texture.resize_tiles(200), Err(VirtualTextureError::TileSizeNotPowerOfTwo(200)));
+        assert_eq!(texture.resize_tiles(DEFAULT_TILE_SIZE * 4),
+                   Err(VirtualTextureError::CacheTooSmall {
+                       needed: too_big,
+                       got: texture.cache_texture_size(),
+                   }));
+        assert_eq!(texture.tile_size(), DEFAULT_TILE_SIZE);
+        assert_eq!(texture.capacity(), 4);
+        assert!(is_resident(&texture, &tile(0, 0)));
+
+        // Halving the tiles fits 3x3 backing tiles of 130 pixels, and empties the cache.
+        assert_eq!(texture.resize_tiles(DEFAULT_TILE_SIZE / 2), Ok(()));
+        assert_eq!(texture.tile_size(), DEFAULT_TILE_SIZE / 2);
+        assert_eq!(texture.capacity(), 9);
+        assert_eq!(texture.resident_tile_count(), 0);
+        assert!(!is_resident(&texture, &tile(0, 0)));
+        for x in 0..9 {
+            expect_miss(texture.request_tile(&tile(x, 0)));
+        }
+        assert!(texture.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn test_build_rejects_bad_geometry() {
+        let result = VirtualTextureBuilder::new().tile_size(200).build();
+        match result {
+            Err(error) => {
+                assert_eq!(error, VirtualTextureError::TileSizeNotPowerOfTwo(200));
+                assert_eq!(error.to_string(), "tile size 200 isn't a power of two");
+            }
+            Ok(_) => panic!("a tile size of 200 was accepted"),
+        }
+
+        let result = VirtualTextureBuilder::new().cache_texture_size(Vector2I::new(512, 200))
+                                                 .build();
+        match result {
+            Err(error) => {
+                assert_eq!(error, VirtualTextureError::CacheTooSmall {
+                    needed: Vector2I::splat(258),
+                    got: Vector2I::new(512, 200),
+                });
+                assert_eq!(error.to_string(),
+                           "cache texture of 512x200 pixels can't hold a single 258x258 tile");
+            }
+            Ok(_) => panic!("a cache texture smaller than a tile was accepted"),
+        }
     }
 }
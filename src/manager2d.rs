@@ -1,16 +1,40 @@
 // virtex/src/manager2d.rs
 
-use crate::{RequestResult, TileCacheEntry, TileDescriptor, VirtualTexture};
+use crate::{MAX_LOD, MIN_LOD, RequestResult, TileCacheEntry, TileCacheStatus, TileDescriptor};
+use crate::VirtualTexture;
 
 use arrayvec::ArrayVec;
+use log::warn;
 use pathfinder_geometry::transform2d::Transform2F;
 use pathfinder_geometry::rect::RectF;
 use pathfinder_geometry::vector::{Vector2F, Vector2I};
+use std::ops::Range;
+
+const LOD_EPSILON: f32 = 1.0 / 1024.0;
 
 pub struct VirtualTextureManager2D {
     pub texture: VirtualTexture,
     pub transform: Transform2F,
+    /// The coarsest LOD that will be requested. When the scale calls for a coarser LOD, tiles at
+    /// this LOD are minified instead.
+    pub min_lod: i32,
+    /// The finest LOD that will be requested. When the scale calls for a finer LOD, tiles at this
+    /// LOD are magnified instead.
+    pub max_lod: i32,
+    /// The layer that tiles are requested from and rendered from.
+    pub layer: u8,
+    /// The most cache misses a single call to `request_needed_tiles` will report. Coarser LODs
+    /// are requested first; visible tiles past the limit are skipped and get requested on a
+    /// later frame instead.
+    pub max_requests_per_frame: usize,
+    /// Shifts the LOD computed from the scale. Negative values select sharper, finer tiles at the
+    /// cost of memory; positive values select blurrier, coarser ones.
+    pub lod_bias: f32,
+    /// The width, in tiles, of the ring of tiles around the visible ones to prefetch so that
+    /// panning doesn't reveal missing tiles.
+    pub margin_tiles: i32,
     viewport_size: Vector2I,
+    transform_was_singular: bool,
 }
 
 impl VirtualTextureManager2D {
@@ -20,53 +44,662 @@ impl VirtualTextureManager2D {
             texture,
             viewport_size,
             transform: Transform2F::default(),
+            min_lod: MIN_LOD,
+            max_lod: MAX_LOD,
+            layer: 0,
+            max_requests_per_frame: std::usize::MAX,
+            lod_bias: 0.0,
+            margin_tiles: 0,
+            transform_was_singular: false,
         }
     }
 
+    /// The larger of the factors by which the transform scales the two content axes. Rotations
+    /// and flips don't affect it.
     #[inline]
     pub fn current_scale(&self) -> f32 {
-        f32::max(self.transform.m11(), self.transform.m22())
+        let matrix = &self.transform.matrix;
+        let x_axis = Vector2F::new(matrix.m11(), matrix.m21());
+        let y_axis = Vector2F::new(matrix.m12(), matrix.m22());
+        f32::max(x_axis.length(), y_axis.length())
+    }
+
+    /// The fractional LOD that the current scale calls for, including the bias.
+    #[inline]
+    pub fn current_lod(&self) -> f32 {
+        // Higher LODs are finer, so a negative bias must raise the LOD.
+        let lod = self.current_scale().log2() - self.lod_bias;
+        // Snap away rounding error, such as from rotations, that would select an extra LOD.
+        let nearest_lod = lod.round();
+        if (lod - nearest_lod).abs() < LOD_EPSILON {
+            nearest_lod
+        } else {
+            lod
+        }
     }
 
+    /// Returns the LODs to request and render, coarser first: the two LODs bracketing the current
+    /// LOD, or just one if the current LOD is an integer. Both are clamped to
+    /// `min_lod..=max_lod`, so when they'd fall outside it, only the LOD at the bound is used.
     pub fn current_lods(&self) -> ArrayVec<[i32; 2]> {
-        let scale = self.current_scale();
-        let lower_lod = 31 - ((scale.floor() as u32).leading_zeros() as i32);
+        let lod = self.current_lod();
+        let lower_lod = self.clamp_lod(lod.floor() as i32);
+        let upper_lod = self.clamp_lod(lod.ceil() as i32);
 
         let mut lods = ArrayVec::new();
         lods.push(lower_lod);
-        if (1 << lower_lod) as f32 != scale {
-            lods.push(lower_lod + 1);
+        if upper_lod != lower_lod {
+            lods.push(upper_lod);
         }
-
         lods
     }
 
+    /// Requests every tile visible at the current LODs, appending cache misses to `needed_tiles`.
+    ///
+    /// Each descriptor appears at most once per call: only the first request for a tile misses,
+    /// and any later request for it within the same frame is a cache hit.
     pub fn request_needed_tiles(&mut self, needed_tiles: &mut Vec<TileCacheEntry>) {
+        self.request_needed_tiles_with_feedback(needed_tiles, None)
+    }
+
+    /// Like `request_needed_tiles`, but if `feedback` is supplied, also appends every visible
+    /// descriptor within the content to it along with the result of requesting it, hits included.
+    /// This is useful for measuring the working set and hit rate.
+    ///
+    /// Tiles skipped because of `max_requests_per_frame` weren't requested, so they're left out.
+    /// Prefetched tiles are left out too.
+    pub fn request_needed_tiles_with_feedback(
+            &mut self,
+            needed_tiles: &mut Vec<TileCacheEntry>,
+            mut feedback: Option<&mut Vec<(TileDescriptor, RequestResult)>>) {
+        // A singular transform has no inverse, and the NaNs or infinities that result would
+        // produce a nonsensical range of tiles. Compare the determinant against the lengths of
+        // the basis vectors rather than against a fixed epsilon, so that tiny but valid scales
+        // pass and only nearly parallel or zero-length axes are rejected.
+        let matrix = &self.transform.matrix;
+        let determinant = matrix.det();
+        let axis_lengths = Vector2F::new(matrix.m11(), matrix.m21()).length() *
+            Vector2F::new(matrix.m12(), matrix.m22()).length();
+        if !determinant.is_finite() || determinant.abs() <= std::f32::EPSILON * axis_lengths {
+            // Animations can pass through a singular transform for many frames; warn only once.
+            if !self.transform_was_singular {
+                warn!("transform {:?} is singular; not requesting any tiles", self.transform);
+                self.transform_was_singular = true;
+            }
+            return;
+        }
+        self.transform_was_singular = false;
+
+        self.texture.begin_frame();
+
         let lods = self.current_lods();
-        println!("lods={:?}", lods);
-        for lod in lods {
-            self.request_needed_tiles_for_lod(needed_tiles, lod);
+        let mut requests_left = self.max_requests_per_frame;
+        for &lod in &lods {
+            self.request_needed_tiles_for_lod(needed_tiles,
+                                              feedback.as_deref_mut(),
+                                              lod,
+                                              &mut requests_left);
+        }
+
+        // Prefetch only once every visible tile has been requested, so that a visible tile's
+        // eviction can't take the slot of a tile prefetched earlier in the same frame.
+        for &lod in &lods {
+            self.prefetch_margin_tiles_for_lod(needed_tiles, lod, &mut requests_left);
+        }
+    }
+
+    /// Returns the tile at the current lower LOD that covers the given viewport position.
+    ///
+    /// This doesn't check the content bounds: positions past the right or bottom edge of the
+    /// content map to tiles that will never be rasterized. Positions above or to the left of the
+    /// content are clamped to the first row or column of tiles.
+    pub fn screen_to_tile(&self, screen_pos: Vector2F) -> TileDescriptor {
+        let lod = self.clamp_lod(self.current_lod().floor() as i32);
+        let tile_size_inv = f32::exp2(lod as f32) / self.texture.tile_size as f32;
+        let tile_pos = (self.transform.inverse() * screen_pos).scale(tile_size_inv);
+        TileDescriptor {
+            x: i32::max(tile_pos.x().floor() as i32, 0),
+            y: i32::max(tile_pos.y().floor() as i32, 0),
+            lod,
+            layer: self.layer,
         }
     }
 
+    /// Returns the bounding rectangle of the tile in viewport coordinates.
+    pub fn tile_to_screen_rect(&self, descriptor: &TileDescriptor) -> RectF {
+        let tile_position = Vector2F::new(descriptor.x as f32, descriptor.y as f32);
+        let scaled_tile_size = self.texture.tile_size as f32 / f32::exp2(descriptor.lod as f32);
+        self.transform * RectF::new(tile_position, Vector2F::splat(1.0)).scale(scaled_tile_size)
+    }
+
+    /// Returns the tiles at `lod` that cover `rect`, given in LOD 0 content coordinates, row by
+    /// row. The LOD is clamped to `min_lod..=max_lod`.
+    pub fn descriptors_for_rect(&self, rect: RectF, lod: i32) -> Vec<TileDescriptor> {
+        let lod = self.clamp_lod(lod);
+        let tile_size_inv = f32::exp2(lod as f32) / self.texture.tile_size as f32;
+        let tile_rect = rect.scale(tile_size_inv);
+        let tile_space_quad = [
+            tile_rect.origin(),
+            tile_rect.upper_right(),
+            tile_rect.lower_right(),
+            tile_rect.lower_left(),
+        ];
+
+        let mut descriptors = vec![];
+        for y in quad_tile_rows(&tile_space_quad) {
+            if let Some((min_x, max_x)) = quad_tile_range_in_row(&tile_space_quad, y) {
+                for x in min_x..max_x {
+                    descriptors.push(TileDescriptor { x, y, lod, layer: self.layer });
+                }
+            }
+        }
+        descriptors
+    }
+
     #[inline]
     pub fn viewport_size(&self) -> Vector2I {
         self.viewport_size
     }
 
-    fn request_needed_tiles_for_lod(&mut self, needed_tiles: &mut Vec<TileCacheEntry>, lod: i32) {
-        let viewport_rect = RectF::new(Vector2F::default(), self.viewport_size.to_f32());
-        let transformed_viewport_rect = self.transform.inverse() * viewport_rect;
-        let tile_size_inv = ((1 << lod) as f32) / self.texture.tile_size as f32;
-        let tile_space_rect = transformed_viewport_rect.scale(tile_size_inv).round_out().to_i32();
-        println!("tile space rect={:?}", tile_space_rect);
-        for y in tile_space_rect.min_y()..tile_space_rect.max_y() {
-            for x in tile_space_rect.min_x()..tile_space_rect.max_x() {
-                let descriptor = TileDescriptor { x, y, lod };
-                if let RequestResult::CacheMiss(address) = self.texture.request_tile(&descriptor) {
-                    needed_tiles.push(TileCacheEntry { descriptor, address });
+    /// Call this when the window is resized. The tiles for the new viewport are requested on the
+    /// next call to `request_needed_tiles`.
+    #[inline]
+    pub fn set_viewport_size(&mut self, viewport_size: Vector2I) {
+        self.viewport_size = viewport_size;
+    }
+
+    #[inline]
+    fn lod_is_in_range(&self, lod: i32) -> bool {
+        lod >= self.min_lod && lod <= self.max_lod
+    }
+
+    // Clamps to `min_lod..=max_lod`, and to the LODs that descriptors can represent.
+    #[inline]
+    fn clamp_lod(&self, lod: i32) -> i32 {
+        let min_lod = i32::max(self.min_lod, MIN_LOD);
+        let max_lod = i32::min(self.max_lod, MAX_LOD);
+        i32::max(i32::min(lod, max_lod), min_lod)
+    }
+
+    // Returns false if the tile lies entirely outside the content, and would thus be rasterized
+    // to nothing but background.
+    fn tile_is_in_content(&self, descriptor: &TileDescriptor) -> bool {
+        let content_size = self.texture.virtual_texture_size();
+        if content_size.x() <= 0 || content_size.y() <= 0 {
+            return true;
+        }
+        let scaled_tile_size = self.texture.tile_size as f32 / f32::exp2(descriptor.lod as f32);
+        descriptor.x >= 0 && descriptor.y >= 0 &&
+            (descriptor.x as f32 * scaled_tile_size) < content_size.x() as f32 &&
+            (descriptor.y as f32 * scaled_tile_size) < content_size.y() as f32
+    }
+
+    // Returns the columns and rows of tiles at `lod` that overlap the content. These are
+    // unbounded if the content size is unknown.
+    fn content_tile_range(&self, lod: i32) -> (Range<i32>, Range<i32>) {
+        let content_size = self.texture.virtual_texture_size();
+        if content_size.x() <= 0 || content_size.y() <= 0 {
+            return (std::i32::MIN..std::i32::MAX, std::i32::MIN..std::i32::MAX);
+        }
+        let tile_size_inv = f32::exp2(lod as f32) / self.texture.tile_size as f32;
+        let content_tiles = content_size.to_f32().scale(tile_size_inv).ceil();
+        (0..(content_tiles.x() as i32), 0..(content_tiles.y() as i32))
+    }
+
+    fn request_needed_tiles_for_lod(
+            &mut self,
+            needed_tiles: &mut Vec<TileCacheEntry>,
+            mut feedback: Option<&mut Vec<(TileDescriptor, RequestResult)>>,
+            lod: i32,
+            requests_left: &mut usize) {
+        if !self.lod_is_in_range(lod) {
+            return;
+        }
+
+        // When the transform rotates, the viewport quad isn't axis-aligned in tile space, so walk
+        // it one row of tiles at a time rather than requesting every tile in its bounding box.
+        // Clip to the content first, so that zooming far out doesn't walk a vast number of tiles
+        // outside it.
+        let tile_space_quad = self.viewport_quad_in_tile_space(lod);
+        let (content_columns, content_rows) = self.content_tile_range(lod);
+        let tile_rows = intersect_ranges(quad_tile_rows(&tile_space_quad), &content_rows);
+
+        // Reserve room for every visible tile up front. No more than a cacheful can miss in a
+        // sensible frame, so don't reserve more than that.
+        let (mut min_x, mut max_x) = (tile_space_quad[0].x(), tile_space_quad[0].x());
+        for vertex in &tile_space_quad[1..] {
+            min_x = f32::min(min_x, vertex.x());
+            max_x = f32::max(max_x, vertex.x());
+        }
+        let tile_columns = intersect_ranges((min_x.floor() as i32)..(max_x.ceil() as i32),
+                                            &content_columns);
+        let max_needed_tiles = tile_columns.len()
+                                           .saturating_mul(tile_rows.len())
+                                           .min(*requests_left)
+                                           .min(self.texture.capacity() as usize);
+        needed_tiles.reserve(max_needed_tiles);
+
+        for y in tile_rows {
+            let (min_x, max_x) = match quad_tile_range_in_row(&tile_space_quad, y) {
+                None => continue,
+                Some(x_range) => x_range,
+            };
+            for x in intersect_ranges(min_x..max_x, &content_columns) {
+                let descriptor = TileDescriptor { x, y, lod, layer: self.layer };
+                let result = match self.request_tile(&descriptor,
+                                                     false,
+                                                     needed_tiles,
+                                                     requests_left) {
+                    None => continue,
+                    Some(result) => result,
+                };
+                if let Some(ref mut feedback) = feedback {
+                    feedback.push((descriptor, result));
+                }
+            }
+        }
+    }
+
+    // Prefetches the ring of tiles around the visible ones. Each row's ring covers the tiles
+    // within `margin_tiles` of any visible tile in the nearby rows.
+    fn prefetch_margin_tiles_for_lod(&mut self,
+                                     needed_tiles: &mut Vec<TileCacheEntry>,
+                                     lod: i32,
+                                     requests_left: &mut usize) {
+        let margin = self.margin_tiles;
+        if margin <= 0 || !self.lod_is_in_range(lod) {
+            return;
+        }
+
+        let tile_space_quad = self.viewport_quad_in_tile_space(lod);
+        let (content_columns, content_rows) = self.content_tile_range(lod);
+        let tile_rows = quad_tile_rows(&tile_space_quad);
+        let margin_rows = tile_rows.start.saturating_sub(margin)..
+            tile_rows.end.saturating_add(margin);
+        for y in intersect_ranges(margin_rows, &content_rows) {
+            let mut margin_range: Option<(i32, i32)> = None;
+            for neighbor_y in (y - margin)..=(y + margin) {
+                if !tile_rows.contains(&neighbor_y) {
+                    continue;
+                }
+                if let Some((min_x, max_x)) = quad_tile_range_in_row(&tile_space_quad, neighbor_y) {
+                    margin_range = Some(match margin_range {
+                        None => (min_x, max_x),
+                        Some((old_min_x, old_max_x)) => {
+                            (i32::min(old_min_x, min_x), i32::max(old_max_x, max_x))
+                        }
+                    });
                 }
             }
+            let (min_x, max_x) = match margin_range {
+                None => continue,
+                Some(x_range) => x_range,
+            };
+
+            let visible_range = if tile_rows.contains(&y) {
+                quad_tile_range_in_row(&tile_space_quad, y)
+            } else {
+                None
+            };
+            let margin_columns = min_x.saturating_sub(margin)..max_x.saturating_add(margin);
+            for x in intersect_ranges(margin_columns, &content_columns) {
+                if let Some((visible_min_x, visible_max_x)) = visible_range {
+                    if x >= visible_min_x && x < visible_max_x {
+                        continue;
+                    }
+                }
+                let descriptor = TileDescriptor { x, y, lod, layer: self.layer };
+                self.request_tile(&descriptor, true, needed_tiles, requests_left);
+            }
+        }
+    }
+
+    // Transforms the corners of the viewport into the tile coordinates of `lod`.
+    fn viewport_quad_in_tile_space(&self, lod: i32) -> [Vector2F; 4] {
+        let viewport_rect = RectF::new(Vector2F::default(), self.viewport_size.to_f32());
+        let inverse_transform = self.transform.inverse();
+        let tile_size_inv = f32::exp2(lod as f32) / self.texture.tile_size as f32;
+        [
+            (inverse_transform * viewport_rect.origin()).scale(tile_size_inv),
+            (inverse_transform * viewport_rect.upper_right()).scale(tile_size_inv),
+            (inverse_transform * viewport_rect.lower_right()).scale(tile_size_inv),
+            (inverse_transform * viewport_rect.lower_left()).scale(tile_size_inv),
+        ]
+    }
+
+    // Requests or prefetches one tile, returning `None` if it was skipped because it would have
+    // exceeded `max_requests_per_frame`.
+    fn request_tile(&mut self,
+                    descriptor: &TileDescriptor,
+                    prefetch: bool,
+                    needed_tiles: &mut Vec<TileCacheEntry>,
+                    requests_left: &mut usize)
+                    -> Option<RequestResult> {
+        if !self.tile_is_in_content(descriptor) {
+            return None;
+        }
+        if *requests_left == 0 {
+            if let TileCacheStatus::Empty = self.texture.tile_status(descriptor) {
+                return None;
+            }
+        }
+        let result = if prefetch {
+            self.texture.prefetch_tile(descriptor)
+        } else {
+            self.texture.request_tile(descriptor)
+        };
+        if let RequestResult::CacheMiss(address) = result {
+            needed_tiles.push(TileCacheEntry { descriptor: *descriptor, address });
+            *requests_left -= 1;
+        }
+        Some(result)
+    }
+}
+
+// Returns the part of `range` that also lies within `other`, which may be empty.
+fn intersect_ranges(range: Range<i32>, other: &Range<i32>) -> Range<i32> {
+    i32::max(range.start, other.start)..i32::min(range.end, other.end)
+}
+
+// Returns the range of tile rows that `quad` touches.
+fn quad_tile_rows(quad: &[Vector2F; 4]) -> Range<i32> {
+    let (mut min_y, mut max_y) = (quad[0].y(), quad[0].y());
+    for vertex in &quad[1..] {
+        min_y = f32::min(min_y, vertex.y());
+        max_y = f32::max(max_y, vertex.y());
+    }
+    (min_y.floor() as i32)..(max_y.ceil() as i32)
+}
+
+// Returns the range of tile columns that the convex `quad` touches within the row of tiles `y`.
+fn quad_tile_range_in_row(quad: &[Vector2F; 4], y: i32) -> Option<(i32, i32)> {
+    let (min_x, max_x) = quad_x_range_in_row(quad, y)?;
+    Some((min_x.floor() as i32, max_x.ceil() as i32))
+}
+
+// Returns the horizontal extent of the part of the convex `quad` that lies within the row of
+// tiles `y..(y + 1)`, or `None` if the quad doesn't touch that row.
+fn quad_x_range_in_row(quad: &[Vector2F; 4], y: i32) -> Option<(f32, f32)> {
+    let (row_min_y, row_max_y) = (y as f32, (y + 1) as f32);
+    let mut x_range: Option<(f32, f32)> = None;
+    for (index, &from) in quad.iter().enumerate() {
+        let to = quad[(index + 1) % quad.len()];
+
+        // Clip the edge to the row.
+        let (mut t_min, mut t_max) = (0.0, 1.0);
+        let delta_y = to.y() - from.y();
+        if delta_y == 0.0 {
+            if from.y() < row_min_y || from.y() > row_max_y {
+                continue;
+            }
+        } else {
+            let t_a = (row_min_y - from.y()) / delta_y;
+            let t_b = (row_max_y - from.y()) / delta_y;
+            t_min = f32::max(t_min, f32::min(t_a, t_b));
+            t_max = f32::min(t_max, f32::max(t_a, t_b));
+            if t_min > t_max {
+                continue;
+            }
+        }
+
+        let delta_x = to.x() - from.x();
+        for &t in &[t_min, t_max] {
+            let x = from.x() + delta_x * t;
+            x_range = Some(match x_range {
+                None => (x, x),
+                Some((min_x, max_x)) => (f32::min(min_x, x), f32::max(max_x, x)),
+            });
+        }
+    }
+    x_range
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualTextureBuilder;
+
+    const TILE_SIZE: u32 = 256;
+
+    fn manager(viewport_size: Vector2I) -> VirtualTextureManager2D {
+        let texture = VirtualTextureBuilder::new().cache_texture_size(Vector2I::splat(258 * 16))
+                                                  .tile_size(TILE_SIZE)
+                                                  .build()
+                                                  .unwrap();
+        VirtualTextureManager2D::new(texture, viewport_size)
+    }
+
+    #[test]
+    fn test_current_lods_at_various_scales() {
+        let mut manager = manager(Vector2I::splat(512));
+        for &(scale, expected_lods) in &[
+            (0.25, &[-2][..]),
+            (0.5, &[-1][..]),
+            (1.0, &[0][..]),
+            (1.5, &[0, 1][..]),
+            (4.0, &[2][..]),
+        ] {
+            manager.transform = Transform2F::from_uniform_scale(scale);
+            assert_eq!(&manager.current_lods()[..], expected_lods, "at scale {}", scale);
+        }
+    }
+
+    #[test]
+    fn test_max_lod_magnifies_instead_of_requesting_finer_lods() {
+        let mut manager = manager(Vector2I::splat(512));
+        manager.max_lod = 0;
+        manager.transform = Transform2F::from_uniform_scale(4.0);
+        assert_eq!(&manager.current_lods()[..], &[0]);
+
+        let mut needed_tiles = vec![];
+        manager.request_needed_tiles(&mut needed_tiles);
+        assert!(!needed_tiles.is_empty());
+        assert!(needed_tiles.iter().all(|entry| entry.descriptor.lod == 0));
+
+        // Between two LODs, only the one that's out of range is dropped.
+        manager.transform = Transform2F::from_uniform_scale(0.75);
+        assert_eq!(&manager.current_lods()[..], &[-1, 0]);
+        manager.transform = Transform2F::from_uniform_scale(1.5);
+        assert_eq!(&manager.current_lods()[..], &[0]);
+    }
+
+    #[test]
+    fn test_tile_covering_viewport_is_requested_once() {
+        let mut manager = manager(Vector2I::splat(TILE_SIZE as i32));
+        let (mut needed_tiles, mut feedback) = (vec![], vec![]);
+        manager.request_needed_tiles_with_feedback(&mut needed_tiles, Some(&mut feedback));
+
+        let expected = TileDescriptor { x: 0, y: 0, lod: 0, layer: 0 };
+        assert_eq!(needed_tiles.len(), 1);
+        assert_eq!(needed_tiles[0].descriptor, expected);
+        assert_eq!(feedback.len(), 1);
+        assert_eq!(feedback[0].0, expected);
+    }
+
+    #[test]
+    fn test_rotation_and_flips_dont_change_lod() {
+        let mut manager = manager(Vector2I::splat(512));
+        for &theta in &[0.25, 0.5, 1.0, 2.0] {
+            let rotation = Transform2F::from_rotation(std::f32::consts::PI * theta);
+            manager.transform = rotation * Transform2F::from_uniform_scale(2.0);
+            assert!((manager.current_scale() - 2.0).abs() < 0.0001, "at {}π", theta);
+            assert_eq!(&manager.current_lods()[..], &[1], "at {}π", theta);
+        }
+        manager.transform = Transform2F::from_scale(Vector2F::new(-2.0, 2.0));
+        assert_eq!(&manager.current_lods()[..], &[1]);
+    }
+
+    #[test]
+    fn test_rotated_viewport_skips_tiles_outside_it() {
+        // Rotate the viewport 45° about its center. Its bounding box in content space covers 4x4
+        // tiles, but the corner tiles lie entirely outside the viewport.
+        let mut manager = manager(Vector2I::splat(512));
+        let center = Vector2F::splat(256.0);
+        manager.transform = Transform2F::from_translation(center) *
+            Transform2F::from_rotation(std::f32::consts::FRAC_PI_4) *
+            Transform2F::from_translation(-center);
+        assert_eq!(&manager.current_lods()[..], &[0]);
+
+        let mut needed_tiles = vec![];
+        manager.request_needed_tiles(&mut needed_tiles);
+        let requested: Vec<(i32, i32)> = needed_tiles.iter().map(|entry| {
+            (entry.descriptor.x, entry.descriptor.y)
+        }).collect();
+        for corner in &[(-1, -1), (2, -1), (-1, 2), (2, 2)] {
+            assert!(!requested.contains(corner), "corner tile {:?} was requested", corner);
+        }
+        for inner in &[(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert!(requested.contains(inner), "tile {:?} wasn't requested", inner);
+        }
+        assert_eq!(requested.len(), 12);
+    }
+
+    #[test]
+    fn test_zero_scale_requests_nothing_but_tiny_scales_work() {
+        let mut manager = manager(Vector2I::splat(512));
+        manager.transform = Transform2F::from_uniform_scale(0.0);
+        let mut needed_tiles = vec![];
+        manager.request_needed_tiles(&mut needed_tiles);
+        assert!(needed_tiles.is_empty());
+
+        // The determinant here is 1e-8, far below `f32::EPSILON`, yet the transform is fine.
+        manager.transform = Transform2F::from_uniform_scale(0.0001);
+        manager.request_needed_tiles(&mut needed_tiles);
+        assert!(!needed_tiles.is_empty());
+        assert!(needed_tiles.iter().all(|entry| entry.descriptor.lod < -12));
+    }
+
+    #[test]
+    fn test_request_cap_defers_finer_tiles_to_later_frames() {
+        // At this scale, one LOD 0 tile and 2x2 LOD 1 tiles are visible.
+        let mut manager = manager(Vector2I::splat(TILE_SIZE as i32));
+        manager.transform = Transform2F::from_uniform_scale(1.5);
+        manager.max_requests_per_frame = 3;
+        assert_eq!(&manager.current_lods()[..], &[0, 1]);
+
+        let mut needed_tiles = vec![];
+        manager.request_needed_tiles(&mut needed_tiles);
+        let lods: Vec<i32> = needed_tiles.iter().map(|entry| entry.descriptor.lod).collect();
+        assert_eq!(lods, vec![0, 1, 1]);
+
+        let mut deferred_tiles = vec![];
+        manager.request_needed_tiles(&mut deferred_tiles);
+        assert_eq!(deferred_tiles.len(), 2);
+        for entry in &deferred_tiles {
+            assert_eq!(entry.descriptor.lod, 1);
+            assert!(needed_tiles.iter().all(|needed| needed.descriptor != entry.descriptor));
+        }
+
+        let mut no_tiles = vec![];
+        manager.request_needed_tiles(&mut no_tiles);
+        assert!(no_tiles.is_empty());
+    }
+
+    #[test]
+    fn test_margin_of_one_prefetches_the_eight_neighbors() {
+        let mut manager = manager(Vector2I::splat(TILE_SIZE as i32));
+        manager.transform = Transform2F::from_translation(Vector2F::splat(-2.0 * TILE_SIZE as f32));
+        manager.margin_tiles = 1;
+
+        let (mut needed_tiles, mut feedback) = (vec![], vec![]);
+        manager.request_needed_tiles_with_feedback(&mut needed_tiles, Some(&mut feedback));
+        assert_eq!(feedback.len(), 1);
+        assert_eq!((feedback[0].0.x, feedback[0].0.y), (2, 2));
+
+        let mut prefetched: Vec<(i32, i32)> = needed_tiles[1..].iter().map(|entry| {
+            (entry.descriptor.x, entry.descriptor.y)
+        }).collect();
+        prefetched.sort();
+        assert_eq!(prefetched, vec![
+            (1, 1), (1, 2), (1, 3), (2, 1), (2, 3), (3, 1), (3, 2), (3, 3),
+        ]);
+    }
+
+    #[test]
+    fn test_descriptors_for_rect_with_negative_coordinates() {
+        let manager = manager(Vector2I::splat(512));
+        let rect = RectF::new(Vector2F::new(-10.0, 100.0), Vector2F::new(300.0, 200.0));
+
+        // At LOD 1, tiles cover 128 content pixels, so x spans -10..290 and y spans 100..300.
+        let descriptors = manager.descriptors_for_rect(rect, 1);
+        let mut expected = vec![];
+        for y in 0..=2 {
+            for x in -1..=2 {
+                expected.push(TileDescriptor { x, y, lod: 1, layer: 0 });
+            }
+        }
+        assert_eq!(descriptors, expected);
+    }
+
+    #[test]
+    fn test_panning_past_content_edge_requests_only_tiles_within_it() {
+        let texture = VirtualTextureBuilder::new().virtual_texture_size(Vector2I::splat(600))
+                                                  .cache_texture_size(Vector2I::splat(258 * 16))
+                                                  .tile_size(TILE_SIZE)
+                                                  .build()
+                                                  .unwrap();
+        let mut manager = VirtualTextureManager2D::new(texture, Vector2I::splat(512));
+        manager.margin_tiles = 1;
+
+        // Past the bottom right edge, and then past the top left edge.
+        for &(offset, ref expected) in &[
+            (-300.0, vec![(1, 1), (1, 2), (2, 1), (2, 2)]),
+            (300.0, vec![(0, 0)]),
+        ] {
+            manager.transform = Transform2F::from_translation(Vector2F::splat(offset));
+            let (mut needed_tiles, mut feedback) = (vec![], vec![]);
+            manager.request_needed_tiles_with_feedback(&mut needed_tiles, Some(&mut feedback));
+            let mut visible: Vec<(i32, i32)> = feedback.iter().map(|&(descriptor, _)| {
+                (descriptor.x, descriptor.y)
+            }).collect();
+            visible.sort();
+            assert_eq!(&visible, expected, "at offset {}", offset);
+            // 600 pixels of content span tiles 0 through 2, margin included.
+            for entry in &needed_tiles {
+                let descriptor = entry.descriptor;
+                assert!((0..3).contains(&descriptor.x) && (0..3).contains(&descriptor.y),
+                        "{:?} is outside the content",
+                        descriptor);
+            }
         }
     }
+
+    #[test]
+    fn test_negative_lod_bias_requests_sharper_tiles() {
+        let mut lods_for_bias = vec![];
+        for &lod_bias in &[0.0, -1.0, 1.0] {
+            let mut manager = manager(Vector2I::splat(TILE_SIZE as i32));
+            manager.transform = Transform2F::from_uniform_scale(2.0);
+            manager.lod_bias = lod_bias;
+
+            let mut needed_tiles = vec![];
+            manager.request_needed_tiles(&mut needed_tiles);
+            assert!(!needed_tiles.is_empty());
+            let current_lods = manager.current_lods();
+            for entry in &needed_tiles {
+                assert_eq!(entry.descriptor.lod, current_lods[0], "with bias {}", lod_bias);
+            }
+            lods_for_bias.push(current_lods[..].to_vec());
+        }
+        assert_eq!(lods_for_bias, vec![vec![1], vec![2], vec![0]]);
+    }
+
+    #[test]
+    fn test_zooming_far_out_only_walks_tiles_within_content() {
+        let texture = VirtualTextureBuilder::new().virtual_texture_size(Vector2I::splat(600))
+                                                  .cache_texture_size(Vector2I::splat(258 * 16))
+                                                  .tile_size(TILE_SIZE)
+                                                  .build()
+                                                  .unwrap();
+        let mut manager = VirtualTextureManager2D::new(texture, Vector2I::splat(512));
+        manager.min_lod = 0;
+        manager.margin_tiles = 1;
+        manager.transform = Transform2F::from_uniform_scale(0.0001);
+
+        // Without clipping, this would visit about 20000x20000 tiles per frame.
+        let (mut needed_tiles, mut feedback) = (vec![], vec![]);
+        manager.request_needed_tiles_with_feedback(&mut needed_tiles, Some(&mut feedback));
+        assert_eq!(feedback.len(), 9);
+        assert_eq!(needed_tiles.len(), 9);
+    }
 }
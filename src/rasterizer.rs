@@ -0,0 +1,180 @@
+// virtex/src/rasterizer.rs
+
+use crate::manager2d::VirtualTextureManager2D;
+use crate::serialize::SerializedCache;
+use crate::{TileCacheEntry, TileCacheStatus, TileDescriptor, VirtualTexture};
+
+use pathfinder_geometry::vector::Vector2I;
+use pathfinder_gpu::TextureFormat;
+
+/// A source of tile pixels.
+///
+/// Implementors produce the contents of a single tile on demand. The returned buffer holds 8-bit
+/// four-channel pixels, in the order given by `channel_order`, for the tile plus its border on
+/// every side, i.e. it is `tile_size + border * 2` pixels across and down.
+pub trait TileRasterizer: Send {
+    fn rasterize(&self, descriptor: TileDescriptor, tile_size: u32, border: u32)
+                 -> Result<Vec<u8>, RasterizeError>;
+
+    /// The size of the content at LOD 0, in pixels.
+    fn content_size(&self) -> Vector2I;
+
+    #[inline]
+    fn channel_order(&self) -> ChannelOrder {
+        ChannelOrder::RGBA
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ChannelOrder {
+    RGBA,
+    BGRA,
+}
+
+impl ChannelOrder {
+    /// The channel order that uploads to a texture of the given format must use, or `None` if the
+    /// format doesn't store 8-bit four-channel pixels.
+    #[inline]
+    pub fn for_texture_format(format: TextureFormat) -> Option<ChannelOrder> {
+        match format {
+            TextureFormat::RGBA8 => Some(ChannelOrder::RGBA),
+            _ => None,
+        }
+    }
+}
+
+/// Reorders the channels of 8-bit four-channel pixels in place. This is a no-op if the two
+/// orders already match.
+pub fn swizzle_pixels(pixels: &mut [u8], src_order: ChannelOrder, dest_order: ChannelOrder) {
+    if src_order == dest_order {
+        return;
+    }
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel.swap(0, 2);
+    }
+}
+
+/// Synchronously rasterizes every tile visible at the manager's current transform and viewport,
+/// returning the resulting cache in RGBA8 form. This is meant for building caches offline: load
+/// the result at runtime with `VirtualTexture::import_cache` and the view needs no rasterization.
+///
+/// The tiles are laid out in a scratch cache with the same geometry as the manager's, which is
+/// left untouched. The ring of `margin_tiles` isn't rasterized, and tiles that don't fit in the
+/// cache are left out.
+pub fn rasterize_all_for_view<R>(rasterizer: &R, manager: &VirtualTextureManager2D)
+                                 -> Result<SerializedCache, RasterizeError>
+                                 where R: TileRasterizer {
+    let texture = &manager.texture;
+    let mut scratch_texture = VirtualTexture::with_geometry(texture.virtual_texture_size(),
+                                                            texture.cache_texture_size(),
+                                                            texture.tile_size(),
+                                                            texture.tile_border());
+    scratch_texture.set_lod_retention_weight(texture.lod_retention_weight());
+    let mut scratch_manager = VirtualTextureManager2D::new(scratch_texture,
+                                                           manager.viewport_size());
+    scratch_manager.transform = manager.transform;
+    scratch_manager.min_lod = manager.min_lod;
+    scratch_manager.max_lod = manager.max_lod;
+    scratch_manager.layer = manager.layer;
+    scratch_manager.lod_bias = manager.lod_bias;
+
+    let mut needed_tiles = vec![];
+    scratch_manager.request_needed_tiles(&mut needed_tiles);
+    let cache_pixels = rasterize_tiles(rasterizer, &scratch_manager.texture, &needed_tiles)?;
+    Ok(scratch_manager.texture.export_cache(&cache_pixels))
+}
+
+// Rasterizes the given tiles into an RGBA8 copy of the cache texture.
+fn rasterize_tiles<R>(rasterizer: &R, texture: &VirtualTexture, needed_tiles: &[TileCacheEntry])
+                      -> Result<Vec<u8>, RasterizeError>
+                      where R: TileRasterizer {
+    let cache_texture_size = texture.cache_texture_size();
+    let mut cache_pixels =
+        vec![0; cache_texture_size.x() as usize * cache_texture_size.y() as usize * 4];
+    for tile_cache_entry in needed_tiles {
+        // Skip tiles that were evicted again to make room for later ones.
+        match texture.tile_status(&tile_cache_entry.descriptor) {
+            TileCacheStatus::Resident(address) if address.0 == tile_cache_entry.address.0 => {}
+            _ => continue,
+        }
+
+        let mut tile_pixels = rasterizer.rasterize(tile_cache_entry.descriptor,
+                                                   texture.tile_size(),
+                                                   texture.tile_border())?;
+        swizzle_pixels(&mut tile_pixels, rasterizer.channel_order(), ChannelOrder::RGBA);
+        let tile_rect = texture.tile_backing_rect(tile_cache_entry.address);
+        texture.write_rect(&mut cache_pixels, tile_rect, &tile_pixels, 4);
+    }
+    Ok(cache_pixels)
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RasterizeError {
+    ParseFailed,
+    SurfaceAllocFailed,
+    RenderFailed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VirtualTextureBuilder;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct SolidRasterizer {
+        tiles_rasterized: AtomicUsize,
+    }
+
+    impl TileRasterizer for SolidRasterizer {
+        fn rasterize(&self, _: TileDescriptor, tile_size: u32, border: u32)
+                     -> Result<Vec<u8>, RasterizeError> {
+            self.tiles_rasterized.fetch_add(1, Ordering::Relaxed);
+            let tile_backing_size = (tile_size + border * 2) as usize;
+            Ok(vec![0xff; tile_backing_size * tile_backing_size * 4])
+        }
+
+        fn content_size(&self) -> Vector2I {
+            Vector2I::default()
+        }
+    }
+
+    #[test]
+    fn test_rasterize_all_for_view_skips_margin_and_leaves_cache_alone() {
+        let texture = VirtualTextureBuilder::new().cache_texture_size(Vector2I::splat(258 * 8))
+                                                  .build()
+                                                  .unwrap();
+        let mut manager = VirtualTextureManager2D::new(texture, Vector2I::splat(256));
+        manager.margin_tiles = 1;
+        let resident_tile = TileDescriptor { x: 9, y: 9, lod: 3, layer: 0 };
+        manager.texture.request_tile(&resident_tile);
+
+        let rasterizer = SolidRasterizer { tiles_rasterized: AtomicUsize::new(0) };
+        let cache = rasterize_all_for_view(&rasterizer, &manager).unwrap();
+        assert_eq!(rasterizer.tiles_rasterized.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.tiles.len(), 1);
+        assert_eq!(cache.tiles[0].descriptor, TileDescriptor { x: 0, y: 0, lod: 0, layer: 0 });
+
+        assert_eq!(manager.texture.resident_tile_count(), 1);
+        match manager.texture.tile_status(&resident_tile) {
+            TileCacheStatus::Resident(_) => {}
+            TileCacheStatus::Empty => panic!("the caller's cache was disturbed"),
+        }
+    }
+
+    #[test]
+    fn test_swizzle_raqote_pixels_into_rgba8() {
+        // raqote stores premultiplied 0xAARRGGBB words, which are written out little-endian.
+        let argb: u32 = 0x80402010;
+        let mut pixels = argb.to_le_bytes().to_vec();
+        assert_eq!(pixels, vec![0x10, 0x20, 0x40, 0x80]);
+
+        let cache_order = ChannelOrder::for_texture_format(TextureFormat::RGBA8).unwrap();
+        swizzle_pixels(&mut pixels, ChannelOrder::BGRA, cache_order);
+        assert_eq!(pixels, vec![0x40, 0x20, 0x10, 0x80]);
+
+        // Matching orders leave the pixels alone.
+        swizzle_pixels(&mut pixels, ChannelOrder::RGBA, ChannelOrder::RGBA);
+        assert_eq!(pixels, vec![0x40, 0x20, 0x10, 0x80]);
+        assert_eq!(ChannelOrder::for_texture_format(TextureFormat::R8), None);
+    }
+}
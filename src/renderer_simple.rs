@@ -1,12 +1,14 @@
 // virtex/src/render_simple.rs
 
 use crate::manager2d::VirtualTextureManager2D;
+use crate::TileDescriptor;
 
 use pathfinder_content::color::ColorF;
 use pathfinder_geometry::rect::{RectF, RectI};
 use pathfinder_geometry::vector::{Vector2F, Vector2I};
 use pathfinder_gpu::resources::ResourceLoader;
-use pathfinder_gpu::{BlendState, BufferData, BufferTarget, BufferUploadMode, ClearOps, Device, Primitive, RenderOptions, RenderState, RenderTarget, TextureFormat, UniformData, VertexAttrClass, VertexAttrDescriptor, VertexAttrType};
+use pathfinder_gpu::{BlendState, BufferData, BufferTarget, BufferUploadMode, ClearOps, Device, Primitive, RenderOptions, RenderState, RenderTarget, TextureData, TextureFormat, UniformData, VertexAttrClass, VertexAttrDescriptor, VertexAttrType};
+use std::mem;
 
 static QUAD_VERTEX_POSITIONS: [u8; 8] = [0, 0, 1, 0, 0, 1, 1, 1];
 static QUAD_VERTEX_INDICES: [u32; 6] = [0, 1, 2, 1, 3, 2];
@@ -14,115 +16,216 @@ static QUAD_VERTEX_INDICES: [u32; 6] = [0, 1, 2, 1, 3, 2];
 pub struct SimpleRenderer<D> where D: Device {
     manager: VirtualTextureManager2D,
     render_vertex_array: RenderVertexArray<D>,
-    cache_texture: D::Texture,
+    // The cache texture is attached to a framebuffer so that it can be read back.
+    cache_framebuffer: D::Framebuffer,
+    cache_format: TextureFormat,
 }
 
 impl<D> SimpleRenderer<D> where D: Device {
-    pub fn new(device: &D, manager: VirtualTextureManager2D, resource_loader: &dyn ResourceLoader)
+    pub fn new(device: &D,
+               manager: VirtualTextureManager2D,
+               resource_loader: &dyn ResourceLoader,
+               cache_format: TextureFormat)
                -> SimpleRenderer<D> {
-        let cache_texture = device.create_texture(TextureFormat::RGBA8,
+        // Validate the format up front.
+        bytes_per_pixel(cache_format);
+
+        let cache_texture = device.create_texture(cache_format,
                                                   manager.texture.cache_texture_size());
+        let cache_framebuffer = device.create_framebuffer(cache_texture);
         let render_vertex_array = RenderVertexArray::new(device, resource_loader);
-        SimpleRenderer { manager, render_vertex_array, cache_texture }
+        SimpleRenderer { manager, render_vertex_array, cache_framebuffer, cache_format }
     }
 
     pub fn render(&mut self, device: &D) {
+        let viewport = RectI::new(Vector2I::splat(0), self.manager.viewport_size());
+        self.render_to(device, &RenderTarget::Default, viewport);
+    }
+
+    /// Composites the resident tiles into `target`, for example an offscreen framebuffer.
+    pub fn render_to(&mut self, device: &D, target: &RenderTarget<D>, viewport: RectI) {
         let tile_size = self.manager.texture.tile_size();
-        let tile_backing_size = self.manager.texture.tile_backing_size();
+        let tile_border = self.manager.texture.tile_border();
 
         device.begin_commands();
         let mut cleared = false;
 
         // Render the two LODs in order.
-        let current_scale = self.manager.current_scale();
-        let current_lod = current_scale.log2();
-        println!("current_lod = {}", current_lod);
+        let current_lod = self.manager.current_lod();
         let current_lods = self.manager.current_lods();
-        let high_lod_opacity = current_lod.fract();
+        let high_lod_opacity = current_lod - current_lod.floor();
+
+        let cache_tex_size = self.manager.texture.cache_texture_size();
+        let cache_tex_scale = Vector2F::new(1.0 / cache_tex_size.x() as f32,
+                                            1.0 / cache_tex_size.y() as f32);
 
+        let layer = self.manager.layer;
+        let mut instances = vec![];
         for (render_lod_index, &render_lod) in current_lods.iter().enumerate() {
             let opacity = if render_lod_index == 0 { 1.0 } else { high_lod_opacity };
-            for tile_cache_entry in self.manager.texture.all_cached_tiles() {
-                let descriptor = &tile_cache_entry.descriptor;
-                if descriptor.lod != render_lod {
+
+            instances.clear();
+            for (descriptor, _, tile_backing_rect) in self.manager.texture.cached_tiles() {
+                if descriptor.lod != render_lod || descriptor.layer != layer {
                     continue;
                 }
 
                 let tile_position = Vector2F::new(descriptor.x as f32, descriptor.y as f32);
-                let scaled_tile_size = tile_size as f32 / (1 << render_lod) as f32;
+                let scaled_tile_size = tile_size as f32 / f32::exp2(render_lod as f32);
                 let tile_rect = RectF::new(tile_position,
                                            Vector2F::splat(1.0)).scale(scaled_tile_size);
 
-                let tile_tex_origin = Vector2I::splat(1) +
-                    tile_cache_entry.address.0.scale(tile_backing_size as i32);
+                let tile_tex_origin = tile_backing_rect.origin() +
+                    Vector2I::splat(tile_border as i32);
                 let tile_tex_size = Vector2I::splat(tile_size as i32);
-
-                let cache_tex_size = self.manager.texture.cache_texture_size();
-                let cache_tex_scale = Vector2F::new(1.0 / cache_tex_size.x() as f32,
-                                                    1.0 / cache_tex_size.y() as f32);
                 let tile_tex_rect =
                     RectI::new(tile_tex_origin, tile_tex_size).to_f32().scale_xy(cache_tex_scale);
 
-                //println!("tile_tex_rect={:?}", tile_tex_rect);
-                device.draw_elements(QUAD_VERTEX_INDICES.len() as u32, &RenderState {
-                    target: &RenderTarget::Default,
-                    program: &self.render_vertex_array.render_program.program,
-                    vertex_array: &self.render_vertex_array.vertex_array,
-                    primitive: Primitive::Triangles,
-                    uniforms: &[
-                        (&self.render_vertex_array.render_program.tile_rect_uniform,
-                         UniformData::Vec4(tile_rect.0)),
-                        (&self.render_vertex_array.render_program.tile_tex_rect_uniform,
-                         UniformData::Vec4(tile_tex_rect.0)),
-                        (&self.render_vertex_array.render_program.framebuffer_size_uniform,
-                         UniformData::Vec2(self.manager.viewport_size().to_f32().0)),
-                        (&self.render_vertex_array.render_program.transform_uniform,
-                         UniformData::Mat2(self.manager.transform.matrix.0)),
-                        (&self.render_vertex_array.render_program.translation_uniform,
-                         UniformData::Vec2(self.manager.transform.vector.0)),
-                        (&self.render_vertex_array.render_program.opacity_uniform,
-                         UniformData::Float(opacity)),
-                        (&self.render_vertex_array.render_program.tile_cache_uniform,
-                         UniformData::TextureUnit(0)),
-                    ],
-                    textures: &[&self.cache_texture],
-                    viewport: RectI::new(Vector2I::splat(0), self.manager.viewport_size()),
-                    options: RenderOptions {
-                        clear_ops: ClearOps {
-                            color: if !cleared {
-                                Some(ColorF::new(0.0, 0.0, 0.0, 1.0))
-                            } else {
-                                None
-                            },
-                            ..ClearOps::default()
-                        },
-                        blend: if render_lod_index == 0 {
-                            BlendState::Off
+                instances.push(TileInstance {
+                    tile_rect: rect_to_array(tile_rect),
+                    tile_tex_rect: rect_to_array(tile_tex_rect),
+                    opacity,
+                });
+            }
+
+            // Even with nothing to draw, the first pass has to run to clear the target.
+            if instances.is_empty() && cleared {
+                continue;
+            }
+
+            device.allocate_buffer(&self.render_vertex_array.instance_buffer,
+                                   BufferData::Memory(&instances),
+                                   BufferTarget::Vertex,
+                                   BufferUploadMode::Dynamic);
+
+            device.draw_elements_instanced(QUAD_VERTEX_INDICES.len() as u32,
+                                           instances.len() as u32,
+                                           &RenderState {
+                target,
+                program: &self.render_vertex_array.render_program.program,
+                vertex_array: &self.render_vertex_array.vertex_array,
+                primitive: Primitive::Triangles,
+                uniforms: &[
+                    (&self.render_vertex_array.render_program.framebuffer_size_uniform,
+                     UniformData::Vec2(viewport.size().to_f32().0)),
+                    (&self.render_vertex_array.render_program.transform_uniform,
+                     UniformData::Mat2(self.manager.transform.matrix.0)),
+                    (&self.render_vertex_array.render_program.translation_uniform,
+                     UniformData::Vec2(self.manager.transform.vector.0)),
+                    (&self.render_vertex_array.render_program.tile_cache_uniform,
+                     UniformData::TextureUnit(0)),
+                ],
+                textures: &[device.framebuffer_texture(&self.cache_framebuffer)],
+                viewport,
+                options: RenderOptions {
+                    clear_ops: ClearOps {
+                        color: if !cleared {
+                            Some(ColorF::new(0.0, 0.0, 0.0, 1.0))
                         } else {
-                            BlendState::RGBOneAlphaOneMinusSrcAlpha
+                            None
                         },
-                        ..RenderOptions::default()
+                        ..ClearOps::default()
                     },
-                });
+                    // Tiles hold premultiplied alpha and may be transparent, so even the base
+                    // LOD is composited over the clear color. Opaque tiles are unaffected.
+                    blend: BlendState::RGBOneAlphaOneMinusSrcAlpha,
+                    ..RenderOptions::default()
+                },
+            });
 
-                cleared = true;
-            }
+            cleared = true;
         }
 
         device.end_commands();
     }
 
+    /// Reads back the pixels of one resident tile, or returns `None` if it isn't resident. Like
+    /// `read_cache_texture`, this stalls the pipeline.
+    pub fn read_tile(&self, device: &D, descriptor: &TileDescriptor, include_border: bool)
+                     -> Option<TextureData> {
+        let tile_rect = self.manager.texture.tile_rect_in_cache(descriptor, include_border)?;
+        Some(device.read_pixels(&RenderTarget::Framebuffer(&self.cache_framebuffer), tile_rect))
+    }
+
+    /// Recreates every GPU resource and empties the cache so that all tiles are requested and
+    /// rasterized again. Callers must call this after the GL context is lost and recreated, or
+    /// the renderer will draw with dead resources.
+    pub fn on_context_lost(&mut self, device: &D, resource_loader: &dyn ResourceLoader) {
+        let cache_texture = device.create_texture(self.cache_format,
+                                                  self.manager.texture.cache_texture_size());
+        self.cache_framebuffer = device.create_framebuffer(cache_texture);
+        self.render_vertex_array = RenderVertexArray::new(device, resource_loader);
+        self.manager.texture.clear();
+    }
+
     #[inline]
     pub fn manager_mut(&mut self) -> &mut VirtualTextureManager2D {
         &mut self.manager
     }
 
     #[inline]
-    pub fn cache_texture(&self) -> &D::Texture {
-        &self.cache_texture
+    pub fn cache_texture<'a>(&'a self, device: &D) -> &'a D::Texture {
+        device.framebuffer_texture(&self.cache_framebuffer)
+    }
+
+    /// Reads back the entire cache texture. This stalls the pipeline, so it's only suitable for
+    /// debugging and tests.
+    pub fn read_cache_texture(&self, device: &D) -> TextureData {
+        let cache_rect = RectI::new(Vector2I::splat(0), self.manager.texture.cache_texture_size());
+        device.read_pixels(&RenderTarget::Framebuffer(&self.cache_framebuffer), cache_rect)
+    }
+
+    #[inline]
+    pub fn cache_format(&self) -> TextureFormat {
+        self.cache_format
+    }
+
+    /// The number of bytes each pixel occupies in data uploaded to the cache texture.
+    #[inline]
+    pub fn cache_bytes_per_pixel(&self) -> u32 {
+        bytes_per_pixel(self.cache_format)
+    }
+
+    /// The GPU memory that the cache texture occupies, in bytes.
+    pub fn gpu_memory_bytes(&self) -> u64 {
+        let cache_texture_size = self.manager.texture.cache_texture_size();
+        cache_texture_size.x() as u64 * cache_texture_size.y() as u64 *
+            self.cache_bytes_per_pixel() as u64
     }
 }
 
+fn bytes_per_pixel(format: TextureFormat) -> u32 {
+    match format {
+        TextureFormat::R8 => 1,
+        TextureFormat::R16F => 2,
+        TextureFormat::RGBA8 => 4,
+        TextureFormat::RGBA16F => 8,
+        TextureFormat::RGBA32F => {
+            // Linear filtering of 32-bit float textures isn't universally supported, and the
+            // tiles are always sampled bilinearly.
+            panic!("Unsupported cache texture format {:?}: 32-bit float textures aren't \
+                    filterable on all hardware; use RGBA16F instead", format)
+        }
+    }
+}
+
+// Per-instance vertex data for one tile. Must match the instance attributes configured in
+// `RenderVertexArray::new`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct TileInstance {
+    tile_rect: [f32; 4],
+    tile_tex_rect: [f32; 4],
+    opacity: f32,
+}
+
+const TILE_INSTANCE_SIZE: usize = mem::size_of::<TileInstance>();
+
+#[inline]
+fn rect_to_array(rect: RectF) -> [f32; 4] {
+    [rect.min_x(), rect.min_y(), rect.max_x(), rect.max_y()]
+}
+
 struct RenderVertexArray<D> where D: Device {
     render_program: RenderProgram<D>,
     vertex_array: D::VertexArray,
@@ -130,6 +233,7 @@ struct RenderVertexArray<D> where D: Device {
     quad_vertex_positions_buffer: D::Buffer,
     #[allow(dead_code)]
     quad_vertex_indices_buffer: D::Buffer,
+    instance_buffer: D::Buffer,
 }
 
 impl<D> RenderVertexArray<D> where D: Device {
@@ -159,11 +263,49 @@ impl<D> RenderVertexArray<D> where D: Device {
                                          divisor: 0,
                                          buffer_index: 0,
                                      });
+
+        let instance_buffer = device.create_buffer();
+        device.bind_buffer(&vertex_array, &instance_buffer, BufferTarget::Vertex);
+        device.configure_vertex_attr(&vertex_array,
+                                     &render_program.tile_rect_attribute,
+                                     &VertexAttrDescriptor {
+                                         size: 4,
+                                         class: VertexAttrClass::Float,
+                                         attr_type: VertexAttrType::F32,
+                                         stride: TILE_INSTANCE_SIZE,
+                                         offset: 0,
+                                         divisor: 1,
+                                         buffer_index: 1,
+                                     });
+        device.configure_vertex_attr(&vertex_array,
+                                     &render_program.tile_tex_rect_attribute,
+                                     &VertexAttrDescriptor {
+                                         size: 4,
+                                         class: VertexAttrClass::Float,
+                                         attr_type: VertexAttrType::F32,
+                                         stride: TILE_INSTANCE_SIZE,
+                                         offset: 16,
+                                         divisor: 1,
+                                         buffer_index: 1,
+                                     });
+        device.configure_vertex_attr(&vertex_array,
+                                     &render_program.opacity_attribute,
+                                     &VertexAttrDescriptor {
+                                         size: 1,
+                                         class: VertexAttrClass::Float,
+                                         attr_type: VertexAttrType::F32,
+                                         stride: TILE_INSTANCE_SIZE,
+                                         offset: 32,
+                                         divisor: 1,
+                                         buffer_index: 1,
+                                     });
+
         RenderVertexArray {
             render_program,
             vertex_array,
             quad_vertex_positions_buffer,
             quad_vertex_indices_buffer,
+            instance_buffer,
         }
     }
 }
@@ -171,36 +313,36 @@ impl<D> RenderVertexArray<D> where D: Device {
 struct RenderProgram<D> where D: Device {
     program: D::Program,
     position_attribute: D::VertexAttr,
-    tile_rect_uniform: D::Uniform,
-    tile_tex_rect_uniform: D::Uniform,
+    tile_rect_attribute: D::VertexAttr,
+    tile_tex_rect_attribute: D::VertexAttr,
+    opacity_attribute: D::VertexAttr,
     framebuffer_size_uniform: D::Uniform,
     transform_uniform: D::Uniform,
     translation_uniform: D::Uniform,
     tile_cache_uniform: D::Uniform,
-    opacity_uniform: D::Uniform,
 }
 
 impl<D> RenderProgram<D> where D: Device {
     fn new(device: &D, resources: &dyn ResourceLoader) -> RenderProgram<D> {
         let program = device.create_program(resources, "render");
         let position_attribute = device.get_vertex_attr(&program, "Position").unwrap();
-        let tile_rect_uniform = device.get_uniform(&program, "TileRect");
-        let tile_tex_rect_uniform = device.get_uniform(&program, "TileTexRect");
+        let tile_rect_attribute = device.get_vertex_attr(&program, "TileRect").unwrap();
+        let tile_tex_rect_attribute = device.get_vertex_attr(&program, "TileTexRect").unwrap();
+        let opacity_attribute = device.get_vertex_attr(&program, "Opacity").unwrap();
         let framebuffer_size_uniform = device.get_uniform(&program, "FramebufferSize");
         let transform_uniform = device.get_uniform(&program, "Transform");
         let translation_uniform = device.get_uniform(&program, "Translation");
         let tile_cache_uniform = device.get_uniform(&program, "TileCache");
-        let opacity_uniform = device.get_uniform(&program, "Opacity");
         RenderProgram {
             program,
             position_attribute,
-            tile_rect_uniform,
-            tile_tex_rect_uniform,
+            tile_rect_attribute,
+            tile_tex_rect_attribute,
+            opacity_attribute,
             framebuffer_size_uniform,
             transform_uniform,
             translation_uniform,
             tile_cache_uniform,
-            opacity_uniform,
         }
     }
 }
@@ -0,0 +1,290 @@
+// virtex/src/renderer_software.rs
+
+use crate::manager2d::VirtualTextureManager2D;
+use crate::TileDescriptor;
+
+use pathfinder_geometry::rect::RectF;
+use pathfinder_geometry::vector::{Vector2F, Vector2I};
+
+/// Composites resident tiles on the CPU, without a GPU device.
+///
+/// This follows the same tile placement and LOD cross-fade as `SimpleRenderer`, but samples the
+/// cache with nearest-neighbor filtering instead of bilinear filtering. It's intended for tests;
+/// it is far too slow for interactive use.
+pub struct SoftwareRenderer {
+    manager: VirtualTextureManager2D,
+    cache_pixels: Vec<u8>,
+}
+
+impl SoftwareRenderer {
+    pub fn new(manager: VirtualTextureManager2D) -> SoftwareRenderer {
+        let cache_texture_size = manager.texture.cache_texture_size();
+        let cache_pixels =
+            vec![0; cache_texture_size.x() as usize * cache_texture_size.y() as usize * 4];
+        SoftwareRenderer { manager, cache_pixels }
+    }
+
+    /// Renders the viewport, returning RGBA8 pixels in row-major order.
+    pub fn render(&self) -> Vec<u8> {
+        let viewport_size = self.manager.viewport_size();
+        let mut pixels = vec![0; viewport_size.x() as usize * viewport_size.y() as usize * 4];
+        for pixel in pixels.chunks_exact_mut(4) {
+            pixel[3] = 255;
+        }
+
+        let tile_size = self.manager.texture.tile_size();
+        let tile_border = self.manager.texture.tile_border();
+        let cache_stride = self.manager.texture.cache_texture_size().x() as usize * 4;
+
+        // Render the two LODs in order.
+        let current_lod = self.manager.current_lod();
+        let current_lods = self.manager.current_lods();
+        let high_lod_opacity = current_lod - current_lod.floor();
+
+        let viewport_rect = RectF::new(Vector2F::default(), viewport_size.to_f32());
+        let inverse_transform = self.manager.transform.inverse();
+        let layer = self.manager.layer;
+
+        for (render_lod_index, &render_lod) in current_lods.iter().enumerate() {
+            let opacity = if render_lod_index == 0 { 1.0 } else { high_lod_opacity };
+            for (descriptor, _, tile_backing_rect) in self.manager.texture.cached_tiles() {
+                if descriptor.lod != render_lod || descriptor.layer != layer {
+                    continue;
+                }
+
+                let tile_position = Vector2F::new(descriptor.x as f32, descriptor.y as f32);
+                let scaled_tile_size = tile_size as f32 / f32::exp2(render_lod as f32);
+                let tile_rect = RectF::new(tile_position,
+                                           Vector2F::splat(1.0)).scale(scaled_tile_size);
+
+                let tile_tex_origin = tile_backing_rect.origin() +
+                    Vector2I::splat(tile_border as i32);
+
+                let screen_rect = match (self.manager.transform * tile_rect)
+                                           .intersection(viewport_rect) {
+                    None => continue,
+                    Some(screen_rect) => screen_rect.round_out().to_i32(),
+                };
+
+                for y in screen_rect.min_y()..screen_rect.max_y() {
+                    for x in screen_rect.min_x()..screen_rect.max_x() {
+                        // Map the pixel center back into the tile.
+                        let pixel_center = Vector2F::new(x as f32 + 0.5, y as f32 + 0.5);
+                        let content_position = inverse_transform * pixel_center;
+                        if !tile_rect.contains_point(content_position) {
+                            continue;
+                        }
+                        let local_position = (content_position - tile_rect.origin())
+                            .scale(1.0 / scaled_tile_size)
+                            .scale(tile_size as f32);
+                        let texel_x = i32::min(local_position.x() as i32, tile_size as i32 - 1);
+                        let texel_y = i32::min(local_position.y() as i32, tile_size as i32 - 1);
+                        let texel = tile_tex_origin + Vector2I::new(texel_x, texel_y);
+
+                        let src_start = texel.y() as usize * cache_stride +
+                            texel.x() as usize * 4;
+                        let dest_start = (y as usize * viewport_size.x() as usize +
+                                          x as usize) * 4;
+                        let src = &self.cache_pixels[src_start..(src_start + 4)];
+                        // Tiles may be transparent, so even the base LOD is blended.
+                        blend_over(&mut pixels[dest_start..(dest_start + 4)], src, opacity);
+                    }
+                }
+            }
+        }
+
+        pixels
+    }
+
+    #[inline]
+    pub fn manager_mut(&mut self) -> &mut VirtualTextureManager2D {
+        &mut self.manager
+    }
+
+    #[inline]
+    pub fn cache_pixels(&self) -> &[u8] {
+        &self.cache_pixels
+    }
+
+    /// Copies out the RGBA8 pixels of one resident tile, or returns `None` if it isn't resident.
+    pub fn read_tile(&self, descriptor: &TileDescriptor, include_border: bool) -> Option<Vec<u8>> {
+        let texture = &self.manager.texture;
+        let tile_rect = texture.tile_rect_in_cache(descriptor, include_border)?;
+        Some(texture.read_rect(&self.cache_pixels, tile_rect, 4))
+    }
+
+    /// The CPU-side cache texture, as RGBA8 pixels in row-major order. Write rasterized tiles
+    /// here instead of uploading them to a GPU texture.
+    #[inline]
+    pub fn cache_pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.cache_pixels
+    }
+}
+
+// Matches `BlendState::RGBOneAlphaOneMinusSrcAlpha` applied to the shader output, which is the
+// texel scaled by the opacity.
+fn blend_over(dest: &mut [u8], src: &[u8], opacity: f32) {
+    let src_alpha = src[3] as f32 / 255.0 * opacity;
+    for channel in 0..3 {
+        let value = src[channel] as f32 * opacity + dest[channel] as f32 * (1.0 - src_alpha);
+        dest[channel] = f32::min(value.round(), 255.0) as u8;
+    }
+    let alpha = src[3] as f32 * opacity + dest[3] as f32;
+    dest[3] = f32::min(alpha.round(), 255.0) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestResult, VirtualTextureBuilder};
+    use pathfinder_geometry::transform2d::Transform2F;
+
+    fn renderer(viewport_size: Vector2I) -> SoftwareRenderer {
+        let texture = VirtualTextureBuilder::new().cache_texture_size(Vector2I::splat(258 * 4))
+                                                  .build()
+                                                  .unwrap();
+        SoftwareRenderer::new(VirtualTextureManager2D::new(texture, viewport_size))
+    }
+
+    // Makes the tile resident and fills it, border included, with a single RGBA8 color.
+    fn upload_solid_tile(renderer: &mut SoftwareRenderer,
+                         descriptor: TileDescriptor,
+                         color: [u8; 4]) {
+        let address = match renderer.manager.texture.request_tile(&descriptor) {
+            RequestResult::CacheMiss(address) => address,
+            result => panic!("expected a miss, got {:?}", result),
+        };
+        let tile_rect = renderer.manager.texture.tile_backing_rect(address);
+        let pixel_count = tile_rect.size().x() as usize * tile_rect.size().y() as usize;
+        let tile_pixels = color.repeat(pixel_count);
+        renderer.manager.texture.write_rect(&mut renderer.cache_pixels, tile_rect, &tile_pixels, 4);
+    }
+
+    #[test]
+    fn test_read_tile_returns_uploaded_pixels() {
+        let texture = VirtualTextureBuilder::new().cache_texture_size(Vector2I::new(258 * 2, 258))
+                                                  .build()
+                                                  .unwrap();
+        let manager = VirtualTextureManager2D::new(texture, Vector2I::splat(256));
+        let mut renderer = SoftwareRenderer::new(manager);
+
+        let descriptor = TileDescriptor { x: 1, y: 2, lod: 0, layer: 0 };
+        let address = match renderer.manager_mut().texture.request_tile(&descriptor) {
+            RequestResult::CacheMiss(address) => address,
+            result => panic!("expected a miss, got {:?}", result),
+        };
+
+        // Give every pixel of the backing tile a distinct value.
+        let tile_pixels: Vec<u8> = (0..(258 * 258)).flat_map(|index: u32| {
+            let [a, b, c, _] = index.to_le_bytes();
+            vec![a, b, c, 255]
+        }).collect();
+        let tile_rect = renderer.manager.texture.tile_backing_rect(address);
+        renderer.manager.texture.write_rect(&mut renderer.cache_pixels, tile_rect, &tile_pixels, 4);
+
+        assert_eq!(renderer.read_tile(&descriptor, true).unwrap(), tile_pixels);
+        let interior = renderer.read_tile(&descriptor, false).unwrap();
+        assert_eq!(interior.len(), 256 * 256 * 4);
+        for y in 0..256 {
+            let src_start = ((y + 1) * 258 + 1) * 4;
+            assert_eq!(&interior[(y * 256 * 4)..((y + 1) * 256 * 4)],
+                       &tile_pixels[src_start..(src_start + 256 * 4)],
+                       "row {}",
+                       y);
+        }
+
+        assert!(renderer.read_tile(&TileDescriptor { lod: 1, ..descriptor }, true).is_none());
+    }
+
+    #[test]
+    fn test_transparent_tiles_composite_over_clear_color() {
+        let mut renderer = renderer(Vector2I::new(512, 256));
+        upload_solid_tile(&mut renderer, TileDescriptor { x: 0, y: 0, lod: 0, layer: 0 }, [0; 4]);
+        // Premultiplied red at half opacity.
+        upload_solid_tile(&mut renderer,
+                          TileDescriptor { x: 1, y: 0, lod: 0, layer: 0 },
+                          [128, 0, 0, 128]);
+
+        let pixels = renderer.render();
+        assert_eq!(&pixels[0..4], &[0, 0, 0, 255]);
+        let right_start = 300 * 4;
+        assert_eq!(&pixels[right_start..(right_start + 4)], &[128, 0, 0, 255]);
+    }
+
+    fn pixel_at(pixels: &[u8], viewport_width: usize, x: usize, y: usize) -> &[u8] {
+        let start = (y * viewport_width + x) * 4;
+        &pixels[start..(start + 4)]
+    }
+
+    #[test]
+    fn test_render_places_tiles_at_integer_lod() {
+        let mut renderer = renderer(Vector2I::new(768, 256));
+        let (red, green) = ([255, 0, 0, 255], [0, 255, 0, 255]);
+        upload_solid_tile(&mut renderer, TileDescriptor { x: 0, y: 0, lod: 0, layer: 0 }, red);
+        upload_solid_tile(&mut renderer, TileDescriptor { x: 1, y: 0, lod: 0, layer: 0 }, green);
+
+        // The third column of tiles isn't resident, so it shows the clear color.
+        let pixels = renderer.render();
+        let clear = [0, 0, 0, 255];
+        for &(x, expected) in &[(0, red), (255, red), (256, green), (511, green), (512, clear)] {
+            assert_eq!(pixel_at(&pixels, 768, x, 128), &expected, "at x={}", x);
+        }
+    }
+
+    #[test]
+    fn test_render_cross_fades_fractional_lod() {
+        let mut renderer = renderer(Vector2I::splat(128));
+        renderer.manager_mut().transform = Transform2F::from_uniform_scale(1.5);
+        upload_solid_tile(&mut renderer,
+                          TileDescriptor { x: 0, y: 0, lod: 0, layer: 0 },
+                          [255, 0, 0, 255]);
+        upload_solid_tile(&mut renderer,
+                          TileDescriptor { x: 0, y: 0, lod: 1, layer: 0 },
+                          [0, 0, 255, 255]);
+
+        // The finer LOD is drawn over the coarser one with the fractional part of the LOD as its
+        // opacity.
+        let current_lod = renderer.manager.current_lod();
+        let high_lod_opacity = current_lod - current_lod.floor();
+        assert!(high_lod_opacity > 0.5 && high_lod_opacity < 0.6);
+        let expected_red = (255.0 * (1.0 - high_lod_opacity)).round() as i32;
+        let expected_blue = (255.0 * high_lod_opacity).round() as i32;
+
+        let pixels = renderer.render();
+        for &(x, y) in &[(0, 0), (64, 64), (127, 127)] {
+            let pixel = pixel_at(&pixels, 128, x, y);
+            assert!((pixel[0] as i32 - expected_red).abs() <= 1, "{:?} at {:?}", pixel, (x, y));
+            assert_eq!(pixel[1], 0);
+            assert!((pixel[2] as i32 - expected_blue).abs() <= 1, "{:?} at {:?}", pixel, (x, y));
+            assert_eq!(pixel[3], 255);
+        }
+    }
+
+    #[test]
+    fn test_render_rotated_transform() {
+        let mut renderer = renderer(Vector2I::splat(512));
+        let colors = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 0, 255]];
+        for (index, &color) in colors.iter().enumerate() {
+            let descriptor = TileDescriptor {
+                x: index as i32 % 2,
+                y: index as i32 / 2,
+                lod: 0,
+                layer: 0,
+            };
+            upload_solid_tile(&mut renderer, descriptor, color);
+        }
+
+        // Rotate a quarter turn clockwise about the center of the viewport, so the top left tile
+        // lands in the top right quadrant, and so on around.
+        let center = Vector2F::splat(256.0);
+        renderer.manager_mut().transform = Transform2F::from_translation(center) *
+            Transform2F::from_rotation(std::f32::consts::FRAC_PI_2) *
+            Transform2F::from_translation(-center);
+
+        let pixels = renderer.render();
+        assert_eq!(pixel_at(&pixels, 512, 384, 128), &colors[0]);
+        assert_eq!(pixel_at(&pixels, 512, 384, 384), &colors[1]);
+        assert_eq!(pixel_at(&pixels, 512, 128, 128), &colors[2]);
+        assert_eq!(pixel_at(&pixels, 512, 128, 384), &colors[3]);
+    }
+}
@@ -0,0 +1,236 @@
+// virtex/src/serialize.rs
+
+use crate::{TileAddress, TileDescriptor, VirtualTexture};
+
+use pathfinder_geometry::rect::RectI;
+use pathfinder_geometry::vector::Vector2I;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Bump this whenever the layout of `SerializedCache` changes.
+pub const SERIALIZED_CACHE_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SerializedCache {
+    pub version: u32,
+    pub tile_size: u32,
+    pub tile_border: u32,
+    pub bytes_per_pixel: u32,
+    /// The resident tiles, most recently used first.
+    pub tiles: Vec<SerializedTile>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SerializedTile {
+    pub descriptor: TileDescriptor,
+    pub address: (i32, i32),
+    /// The pixels of the tile, including its border.
+    pub pixels: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ImportCacheError {
+    VersionMismatch { expected: u32, got: u32 },
+    TileGeometryMismatch,
+    PixelFormatMismatch,
+    /// The output buffer isn't a whole number of bytes per pixel of the cache texture.
+    BufferSizeMismatch,
+    InvalidTile(TileDescriptor),
+}
+
+impl VirtualTexture {
+    /// Captures every resident tile along with its pixels in `cache_pixels`, which must contain
+    /// the entire cache texture.
+    pub fn export_cache(&self, cache_pixels: &[u8]) -> SerializedCache {
+        let bytes_per_pixel = match self.bytes_per_pixel_of(cache_pixels) {
+            None => panic!("The cache pixel buffer doesn't cover the whole cache texture!"),
+            Some(bytes_per_pixel) => bytes_per_pixel,
+        };
+        // Skip stale LRU entries that are no longer in the cache.
+        let tiles = self.lru.iter().filter_map(|&descriptor| {
+            let address = *self.cache.get(&descriptor)?;
+            let tile_rect = self.tile_backing_rect(address);
+            Some(SerializedTile {
+                descriptor,
+                address: (address.0.x(), address.0.y()),
+                pixels: self.read_rect(cache_pixels, tile_rect, bytes_per_pixel),
+            })
+        }).collect();
+
+        SerializedCache {
+            version: SERIALIZED_CACHE_VERSION,
+            tile_size: self.tile_size(),
+            tile_border: self.tile_border(),
+            bytes_per_pixel,
+            tiles,
+        }
+    }
+
+    /// Replaces the contents of the cache with `data`, writing the tile pixels into `out_pixels`,
+    /// which must be large enough to hold the entire cache texture.
+    ///
+    /// The cache is left untouched if `data` doesn't match the geometry of this texture.
+    pub fn import_cache(&mut self, data: &SerializedCache, out_pixels: &mut [u8])
+                        -> Result<(), ImportCacheError> {
+        if data.version != SERIALIZED_CACHE_VERSION {
+            return Err(ImportCacheError::VersionMismatch {
+                expected: SERIALIZED_CACHE_VERSION,
+                got: data.version,
+            });
+        }
+        if data.tile_size != self.tile_size() || data.tile_border != self.tile_border() {
+            return Err(ImportCacheError::TileGeometryMismatch);
+        }
+        match self.bytes_per_pixel_of(out_pixels) {
+            None => return Err(ImportCacheError::BufferSizeMismatch),
+            Some(bytes_per_pixel) if bytes_per_pixel != data.bytes_per_pixel => {
+                return Err(ImportCacheError::PixelFormatMismatch);
+            }
+            Some(_) => {}
+        }
+
+        // Validate everything before we touch the cache.
+        let tile_backing_size = self.tile_backing_size() as usize;
+        let tile_byte_length =
+            tile_backing_size * tile_backing_size * data.bytes_per_pixel as usize;
+        let tiles_across = self.tile_texture_tiles_across() as i32;
+        let tiles_down = self.tile_texture_tiles_down() as i32;
+        let mut seen_addresses = HashSet::new();
+        let mut seen_descriptors = HashSet::new();
+        for tile in &data.tiles {
+            let (x, y) = tile.address;
+            if x < 0 || y < 0 || x >= tiles_across || y >= tiles_down ||
+                    tile.pixels.len() != tile_byte_length ||
+                    !seen_addresses.insert(tile.address) ||
+                    !seen_descriptors.insert(tile.descriptor) {
+                return Err(ImportCacheError::InvalidTile(tile.descriptor));
+            }
+        }
+
+        self.clear();
+
+        // Insert least recently used first so that the LRU order is preserved.
+        for tile in data.tiles.iter().rev() {
+            let address = TileAddress(Vector2I::new(tile.address.0, tile.address.1));
+            self.free_tile_addresses.retain(|free_address| free_address.0 != address.0);
+            self.cache.insert(tile.descriptor, address);
+            self.lru.push_front(tile.descriptor);
+
+            let tile_rect = self.tile_backing_rect(address);
+            self.write_rect(out_pixels, tile_rect, &tile.pixels, data.bytes_per_pixel);
+        }
+
+        Ok(())
+    }
+
+    // Returns `None` if the buffer isn't a whole number of bytes per pixel of the cache texture.
+    fn bytes_per_pixel_of(&self, cache_pixels: &[u8]) -> Option<u32> {
+        let cache_texture_size = self.cache_texture_size();
+        let pixel_count = cache_texture_size.x() as usize * cache_texture_size.y() as usize;
+        if pixel_count == 0 || cache_pixels.is_empty() ||
+                cache_pixels.len() % pixel_count != 0 {
+            return None;
+        }
+        Some((cache_pixels.len() / pixel_count) as u32)
+    }
+
+    pub(crate) fn read_rect(&self, cache_pixels: &[u8], rect: RectI, bytes_per_pixel: u32)
+                            -> Vec<u8> {
+        let stride = self.cache_texture_size().x() as usize * bytes_per_pixel as usize;
+        let row_length = rect.size().x() as usize * bytes_per_pixel as usize;
+        let mut pixels = Vec::with_capacity(row_length * rect.size().y() as usize);
+        for y in rect.min_y()..rect.max_y() {
+            let start = y as usize * stride + rect.min_x() as usize * bytes_per_pixel as usize;
+            pixels.extend_from_slice(&cache_pixels[start..(start + row_length)]);
+        }
+        pixels
+    }
+
+    pub(crate) fn write_rect(&self,
+                             cache_pixels: &mut [u8],
+                             rect: RectI,
+                             pixels: &[u8],
+                             bytes_per_pixel: u32) {
+        let stride = self.cache_texture_size().x() as usize * bytes_per_pixel as usize;
+        let row_length = rect.size().x() as usize * bytes_per_pixel as usize;
+        for (row_index, y) in (rect.min_y()..rect.max_y()).enumerate() {
+            let start = y as usize * stride + rect.min_x() as usize * bytes_per_pixel as usize;
+            let src_start = row_index * row_length;
+            cache_pixels[start..(start + row_length)]
+                .copy_from_slice(&pixels[src_start..(src_start + row_length)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RequestResult, TileCacheStatus, VirtualTextureBuilder};
+
+    const TILES_ACROSS: i32 = 4;
+
+    fn texture() -> VirtualTexture {
+        VirtualTextureBuilder::new().cache_texture_size(Vector2I::new(258 * TILES_ACROSS, 258))
+                                    .build()
+                                    .unwrap()
+    }
+
+    fn tile(x: i32) -> TileDescriptor {
+        TileDescriptor { x, y: 0, lod: 0, layer: 0 }
+    }
+
+    fn cache_pixels(texture: &VirtualTexture) -> Vec<u8> {
+        let size = texture.cache_texture_size();
+        vec![0; size.x() as usize * size.y() as usize * 4]
+    }
+
+    #[test]
+    fn test_export_clear_import_round_trip() {
+        let mut texture = texture();
+        let mut pixels = cache_pixels(&texture);
+        for x in 0..3 {
+            let address = match texture.request_tile(&tile(x)) {
+                RequestResult::CacheMiss(address) => address,
+                result => panic!("expected a miss, got {:?}", result),
+            };
+            let tile_rect = texture.tile_backing_rect(address);
+            let tile_pixels = vec![x as u8 + 1; tile_rect.size().x() as usize *
+                                               tile_rect.size().y() as usize * 4];
+            texture.write_rect(&mut pixels, tile_rect, &tile_pixels, 4);
+        }
+        let exported = texture.export_cache(&pixels);
+
+        texture.clear();
+        let mut imported_pixels = cache_pixels(&texture);
+        texture.import_cache(&exported, &mut imported_pixels).unwrap();
+
+        assert_eq!(texture.resident_tile_count(), 3);
+        assert_eq!(imported_pixels, pixels);
+        for x in 0..3 {
+            match texture.tile_status(&tile(x)) {
+                TileCacheStatus::Resident(address) => {
+                    let original = exported.tiles
+                                           .iter()
+                                           .find(|tile| tile.descriptor.x == x)
+                                           .unwrap();
+                    assert_eq!((address.0.x(), address.0.y()), original.address);
+                }
+                TileCacheStatus::Empty => panic!("tile {} wasn't imported", x),
+            }
+        }
+
+        // The LRU order survives, so the least recently used tile is still evicted first.
+        texture.request_tile(&tile(3));
+        texture.request_tile(&tile(4));
+        assert!(texture.take_evicted().contains(&tile(0)));
+    }
+
+    #[test]
+    fn test_import_rejects_wrongly_sized_buffer() {
+        let mut texture = texture();
+        let exported = texture.export_cache(&cache_pixels(&texture));
+        let mut short_pixels = vec![0; 7];
+        assert_eq!(texture.import_cache(&exported, &mut short_pixels),
+                   Err(ImportCacheError::BufferSizeMismatch));
+    }
+}